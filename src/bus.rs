@@ -0,0 +1,183 @@
+use crate::{protection::Access, Fault, Memory, Value};
+
+/// What the operands of a command are read from and written to.
+///
+/// [`Memory`] is a bus: cells inside a window mapped with [`Memory::map`]
+/// are handled by their [`Device`], all others are plain RAM.
+pub trait Bus {
+    fn read(&mut self, addr: Value) -> Result<Value, Fault>;
+    fn write(&mut self, addr: Value, value: Value) -> Result<(), Fault>;
+}
+
+/// A write handled by a [`Device`], recorded while the journal of a
+/// [`Memory`] is running. Reading a device may change it, so the value the
+/// write replaced is not known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceWrite {
+    pub addr: Value,
+    pub value: Value,
+}
+
+/// A peripheral mapped into a window of the address space.
+///
+/// Offsets are relative to the start of the window. Reads may have side
+/// effects, like taking a character from an input queue.
+pub trait Device: std::fmt::Debug {
+    fn read(&mut self, offset: u16) -> Value;
+    fn write(&mut self, offset: u16, value: Value);
+
+    /// Called after every executed command, returns true to raise an
+    /// interrupt.
+    fn tick(&mut self) -> bool {
+        false
+    }
+
+    /// Called when the machine is reset.
+    fn reset(&mut self) {}
+
+    /// The state kept in a [`Snapshot`](crate::snapshot::Snapshot), devices
+    /// without state keep nothing.
+    fn save(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Restores a state returned by [`Device::save`].
+    fn restore(&mut self, state: &[u8]) -> Result<(), String> {
+        match state {
+            [] => Ok(()),
+            _ => Err("the device keeps no state".to_owned()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Mapping {
+    pub(crate) start: Value,
+    /// One past the last address of the window.
+    pub(crate) end: u32,
+    pub(crate) device: Box<dyn Device>,
+}
+
+impl Mapping {
+    fn contains(&self, addr: Value) -> bool {
+        (*self.start as u32..self.end).contains(&(*addr as u32))
+    }
+}
+
+impl Memory {
+    /// Hands the `len` bytes starting at `start` to `device`, they no longer
+    /// reach the RAM below through a [`Bus`].
+    pub fn map(&mut self, start: Value, len: u16, device: Box<dyn Device>) -> Result<(), String> {
+        let mapping = Mapping {
+            start,
+            end: *start as u32 + len as u32,
+            device,
+        };
+        if len == 0 {
+            return Err(format!("device at {start} maps no bytes"));
+        }
+        if mapping.end > 0x10000 {
+            return Err(format!("device at {start} reaches past 0xffff"));
+        }
+        let overlap = self.devices.iter().find(|other| {
+            (*other.start as u32) < mapping.end && (*start as u32) < other.end
+        });
+        if let Some(other) = overlap {
+            return Err(format!(
+                "device at {start} overlaps the device at {}..{:#06x}",
+                other.start, other.end
+            ));
+        }
+        self.devices.push(mapping);
+        Ok(())
+    }
+
+    /// The devices mapped into the memory, with their windows.
+    pub fn devices(&self) -> impl Iterator<Item = (Value, u32, &dyn Device)> {
+        self.devices
+            .iter()
+            .map(|mapping| (mapping.start, mapping.end, &*mapping.device))
+    }
+
+    // ticks every device, true if any raised an interrupt
+    pub(crate) fn tick_devices(&mut self) -> bool {
+        let mut raised = false;
+        for mapping in &mut self.devices {
+            raised |= mapping.device.tick();
+        }
+        raised
+    }
+
+    pub(crate) fn reset_devices(&mut self) {
+        for mapping in &mut self.devices {
+            mapping.device.reset();
+        }
+    }
+
+    // the state of every device, by the start of its window
+    pub(crate) fn save_devices(&self) -> Vec<(Value, Vec<u8>)> {
+        self.devices
+            .iter()
+            .map(|mapping| (mapping.start, mapping.device.save()))
+            .collect()
+    }
+
+    // restores the devices mapped at the same windows as when the states
+    // were saved
+    pub(crate) fn restore_devices(&mut self, states: &[(Value, Vec<u8>)]) -> Result<(), String> {
+        if states.len() != self.devices.len() {
+            return Err(format!(
+                "{} devices were saved, but {} are mapped",
+                states.len(),
+                self.devices.len()
+            ));
+        }
+        for (start, state) in states {
+            let mapping = self.devices.iter_mut().find(|mapping| mapping.start == *start);
+            let Some(mapping) = mapping else {
+                return Err(format!("no device is mapped at {start}"));
+            };
+            mapping.device.restore(state).map_err(|e| format!("device at {start}: {e}"))?;
+        }
+        Ok(())
+    }
+
+    /// Whether the cell at `addr` is handled by a device.
+    pub fn is_mapped(&self, addr: Value) -> bool {
+        self.devices.iter().any(|mapping| mapping.contains(addr))
+    }
+
+    // the device handling the cell at `addr` and the offset into its window
+    fn device(&mut self, addr: Value) -> Option<(&mut dyn Device, u16)> {
+        let mapping = self
+            .devices
+            .iter_mut()
+            .find(|mapping| mapping.contains(addr))?;
+        let offset = *addr - *mapping.start;
+        Some((&mut *mapping.device, offset))
+    }
+}
+
+impl Bus for Memory {
+    fn read(&mut self, addr: Value) -> Result<Value, Fault> {
+        self.check(addr, 2, Access::Read)?;
+        match self.device(addr) {
+            Some((device, offset)) => Ok(device.read(offset)),
+            None => Memory::read(self, addr),
+        }
+    }
+
+    fn write(&mut self, addr: Value, value: Value) -> Result<(), Fault> {
+        self.check(addr, 2, Access::Write)?;
+        match self.device(addr) {
+            Some((device, offset)) => {
+                device.write(offset, value);
+                if self.journal.is_some() {
+                    self.device_journal.push(DeviceWrite { addr, value });
+                }
+                Ok(())
+            }
+            None => Memory::write(self, addr, value),
+        }
+    }
+}
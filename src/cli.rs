@@ -0,0 +1,365 @@
+use std::{num::ParseIntError, path::PathBuf};
+
+use mini_cpu::{
+    devices::{CONSOLE_BASE, TIMER_BASE},
+    image::Format,
+    protection::Protection,
+    EdgePolicy, Value,
+};
+
+pub const USAGE: &str = "\
+usage: mini-cpu <command> [options] <path>
+
+commands:
+    run       compile a source file, or load an image, and execute it
+    build     compile a source file and write the encoded program
+    check     compile a source file and only report errors
+    disasm    print the commands of a source file or an image
+    debug     compile a source file, or load an image, and step through it
+
+images are recognized by their extension: .bin (flat binary loaded at
+--load), .hex (Intel HEX) and .srec (Motorola S-record).
+
+options:
+    -l, --load <addr>        address the program is loaded at (default: 0xf000)
+    -E, --entry <addr>       start executing at <addr> instead of the entry of
+                             the program, the load address for .bin images
+    -m, --memory <size>      size of the memory in bytes (default: 65536)
+    -s, --max-steps <n>      stop running after <n> commands
+    -e, --edge <policy>      accesses past the end of memory `trap`, `wrap` or
+                             read as `zero` (default: trap)
+    -c, --console <addr>     map the console at <addr>, or at 0xff00 for `on`
+                             (default: off), without input under debug
+    -T, --timer <addr>       map the timer at <addr>, or at 0xff08 for `on`
+                             (default: off)
+    -p, --protect <region>   protect <start>..<end>:<ro|xo|na> as read-only,
+                             execute-only or no-access, may be repeated
+    -r, --rom                make the loaded program read-only
+    -S, --snapshot <path>    continue from a snapshot of the loaded program
+        --save-snapshot <p>  write a snapshot once running stops
+    -o, --output <path>      file to write to (build: <path>.bin, disasm: stdout)
+    -f, --format <format>    image format written by build: bin, hex or srec
+    -y, --symbols <path>     symbol file written by build, read by disasm and debug
+    -L, --layout <path>      write where build and check placed allocations and bytes
+    -t, --trace <sink>       trace executed commands to `stderr` or a file (default: off)
+        --trace-format <f>   trace as `text` lines or `json` lines (default: text)
+    -h, --help               print this message
+";
+
+pub const DEFAULT_LOAD: Value = Value::new(0xf000);
+pub const DEFAULT_MEMORY: usize = 0x10000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subcommand {
+    Run,
+    Build,
+    Check,
+    Disasm,
+    Debug,
+}
+
+/// Where `run` writes its trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceSink {
+    Off,
+    Stderr,
+    File(PathBuf),
+}
+
+#[derive(Debug)]
+pub enum ArgsError {
+    Help,
+    Invalid(String),
+}
+
+#[derive(Debug)]
+pub struct Args {
+    pub command: Subcommand,
+    pub path: PathBuf,
+    pub load: Value,
+    pub entry: Option<Value>,
+    pub memory: usize,
+    pub max_steps: Option<usize>,
+    pub edge: EdgePolicy,
+    pub console: Option<Value>,
+    pub timer: Option<Value>,
+    pub protect: Vec<(Value, u16, Protection)>,
+    pub rom: bool,
+    pub snapshot: Option<PathBuf>,
+    pub save_snapshot: Option<PathBuf>,
+    pub output: Option<PathBuf>,
+    pub format: Option<Format>,
+    pub symbols: Option<PathBuf>,
+    pub layout: Option<PathBuf>,
+    pub trace: TraceSink,
+    pub trace_json: bool,
+}
+
+fn parse_number(s: &str) -> Result<usize, ParseIntError> {
+    if let Some(str) = s.strip_prefix("0x") {
+        usize::from_str_radix(str, 16)
+    } else if let Some(str) = s.strip_prefix("0b") {
+        usize::from_str_radix(str, 2)
+    } else if let Some(str) = s.strip_prefix("0o") {
+        usize::from_str_radix(str, 8)
+    } else {
+        s.parse()
+    }
+}
+
+// `<start>..<end>:<protection>` as the start, length and protection
+fn parse_region(value: &str) -> Result<(Value, u16, Protection), String> {
+    let invalid = || format!("invalid region `{value}`, expect <start>..<end>:<ro|xo|na>");
+    let (range, protection) = value.split_once(':').ok_or_else(invalid)?;
+    let (start, end) = range.split_once("..").ok_or_else(invalid)?;
+    let (Ok(start), Ok(end)) = (parse_number(start), parse_number(end)) else {
+        return Err(invalid());
+    };
+    if start >= end || end > 0x10000 {
+        return Err(format!("region `{range}` must be a non-empty range up to 0x10000"));
+    }
+    let len = u16::try_from(end - start).map_err(|_| format!("region `{range}` is too large"))?;
+    Ok((Value::new(start as u16), len, protection.parse()?))
+}
+
+// the address a device is mapped at, `base` for `on` and `None` for `off`
+fn device_addr(device: &str, base: Value, value: &str) -> Result<Option<Value>, ArgsError> {
+    match value {
+        "on" => Ok(Some(base)),
+        "off" => Ok(None),
+        addr => addr.parse().map(Some).map_err(|e| {
+            ArgsError::Invalid(format!("invalid {device} address `{addr}`: {e}"))
+        }),
+    }
+}
+
+impl Args {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Args, ArgsError> {
+        let mut args = args.into_iter();
+        let invalid = |reason: String| Err(ArgsError::Invalid(reason));
+
+        let command = match args.next().as_deref() {
+            Some("run") => Subcommand::Run,
+            Some("build") => Subcommand::Build,
+            Some("check") => Subcommand::Check,
+            Some("disasm") => Subcommand::Disasm,
+            Some("debug") => Subcommand::Debug,
+            Some("-h" | "--help") => return Err(ArgsError::Help),
+            Some(other) => return invalid(format!("unknown command `{other}`")),
+            None => return invalid("missing command".to_owned()),
+        };
+
+        let mut path = None;
+        let mut load = DEFAULT_LOAD;
+        let mut entry = None;
+        let mut memory = DEFAULT_MEMORY;
+        let mut max_steps = None;
+        let mut edge = EdgePolicy::default();
+        let mut console = None;
+        let mut timer = None;
+        let mut protect = Vec::new();
+        let mut rom = false;
+        let mut snapshot = None;
+        let mut save_snapshot = None;
+        let mut output = None;
+        let mut format = None;
+        let mut symbols = None;
+        let mut layout = None;
+        let mut trace = TraceSink::Off;
+        let mut trace_json = false;
+
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| ArgsError::Invalid(format!("`{arg}` requires a value")))
+            };
+            match arg.as_str() {
+                "-h" | "--help" => return Err(ArgsError::Help),
+                "-l" | "--load" => {
+                    let value = value()?;
+                    load = value.parse().map_err(|e| {
+                        ArgsError::Invalid(format!("invalid load address `{value}`: {e}"))
+                    })?;
+                }
+                "-E" | "--entry" => {
+                    let value = value()?;
+                    let addr = value.parse().map_err(|e| {
+                        ArgsError::Invalid(format!("invalid entry address `{value}`: {e}"))
+                    })?;
+                    entry = Some(addr);
+                }
+                "-m" | "--memory" => {
+                    let value = value()?;
+                    memory = parse_number(&value).map_err(|e| {
+                        ArgsError::Invalid(format!("invalid memory size `{value}`: {e}"))
+                    })?;
+                }
+                "-s" | "--max-steps" => {
+                    let value = value()?;
+                    let steps = parse_number(&value).map_err(|e| {
+                        ArgsError::Invalid(format!("invalid step limit `{value}`: {e}"))
+                    })?;
+                    max_steps = Some(steps);
+                }
+                "-e" | "--edge" => edge = value()?.parse().map_err(ArgsError::Invalid)?,
+                "-c" | "--console" => console = device_addr("console", CONSOLE_BASE, &value()?)?,
+                "-T" | "--timer" => timer = device_addr("timer", TIMER_BASE, &value()?)?,
+                "-p" | "--protect" => {
+                    protect.push(parse_region(&value()?).map_err(ArgsError::Invalid)?);
+                }
+                "-r" | "--rom" => rom = true,
+                "-S" | "--snapshot" => snapshot = Some(PathBuf::from(value()?)),
+                "--save-snapshot" => save_snapshot = Some(PathBuf::from(value()?)),
+                "-o" | "--output" => output = Some(PathBuf::from(value()?)),
+                "-f" | "--format" => {
+                    format = Some(value()?.parse().map_err(ArgsError::Invalid)?);
+                }
+                "-y" | "--symbols" => symbols = Some(PathBuf::from(value()?)),
+                "-L" | "--layout" => layout = Some(PathBuf::from(value()?)),
+                "-t" | "--trace" => {
+                    trace = match value()?.as_str() {
+                        "off" => TraceSink::Off,
+                        "stderr" => TraceSink::Stderr,
+                        path => TraceSink::File(PathBuf::from(path)),
+                    };
+                }
+                "--trace-format" => {
+                    trace_json = match value()?.as_str() {
+                        "text" => false,
+                        "json" => true,
+                        other => return invalid(format!("unknown trace format `{other}`")),
+                    };
+                }
+                flag if flag.starts_with('-') => return invalid(format!("unknown option `{flag}`")),
+                _ if path.is_some() => return invalid(format!("unexpected argument `{arg}`")),
+                _ => path = Some(PathBuf::from(arg)),
+            }
+        }
+
+        let Some(path) = path else {
+            return invalid("missing input path".to_owned());
+        };
+        if !(2..=DEFAULT_MEMORY).contains(&memory) {
+            return invalid(format!(
+                "memory size must be between 2 and {DEFAULT_MEMORY} bytes"
+            ));
+        }
+
+        Ok(Args {
+            command,
+            path,
+            load,
+            entry,
+            memory,
+            max_steps,
+            edge,
+            console,
+            timer,
+            protect,
+            rom,
+            snapshot,
+            save_snapshot,
+            output,
+            format,
+            symbols,
+            layout,
+            trace,
+            trace_json,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Args, ArgsError> {
+        Args::parse(args.split_whitespace().map(str::to_owned))
+    }
+
+    // why `args` are refused
+    fn invalid(args: &str) -> String {
+        match parse(args) {
+            Err(ArgsError::Invalid(reason)) => reason,
+            other => panic!("`{args}` gave {other:?}"),
+        }
+    }
+
+    #[test]
+    fn options_have_defaults() {
+        let args = parse("run code.mc").unwrap();
+        assert_eq!((args.command, args.path), (Subcommand::Run, PathBuf::from("code.mc")));
+        assert_eq!((args.load, args.entry, args.memory), (DEFAULT_LOAD, None, DEFAULT_MEMORY));
+        assert_eq!((args.console, args.timer, args.max_steps), (None, None, None));
+        assert_eq!((args.edge, args.trace), (EdgePolicy::Trap, TraceSink::Off));
+        assert!(args.protect.is_empty() && !args.rom && !args.trace_json);
+        assert!(args.format.is_none() && args.output.is_none() && args.symbols.is_none());
+    }
+
+    #[test]
+    fn help_is_asked_for_anywhere() {
+        for args in ["-h", "--help", "run -h", "build code.mc --help"] {
+            assert!(matches!(parse(args), Err(ArgsError::Help)), "{args}");
+        }
+    }
+
+    #[test]
+    fn bad_arguments_are_refused() {
+        assert_eq!(invalid(""), "missing command");
+        assert_eq!(invalid("walk code.mc"), "unknown command `walk`");
+        assert_eq!(invalid("run"), "missing input path");
+        assert_eq!(invalid("run a.mc b.mc"), "unexpected argument `b.mc`");
+        assert_eq!(invalid("run code.mc --fast"), "unknown option `--fast`");
+        assert_eq!(invalid("run code.mc --load"), "`--load` requires a value");
+        assert!(invalid("run code.mc -l 0x10000").starts_with("invalid load address"));
+        assert!(invalid("run code.mc -E start").starts_with("invalid entry address"));
+    }
+
+    #[test]
+    fn protected_regions_are_parsed() {
+        let args = parse("run code.mc -p 0x10..0x20:ro --protect 0xff00..0x10000:na").unwrap();
+        let expected = [
+            (Value::new(0x10), 0x10, Protection::ReadOnly),
+            (Value::new(0xff00), 0x100, Protection::NoAccess),
+        ];
+        assert_eq!(args.protect, expected);
+
+        let cases = [
+            ("0x10..0x20", "invalid region `0x10..0x20`"),
+            ("0x10-0x20:ro", "invalid region `0x10-0x20:ro`"),
+            ("0x20..0x10:ro", "region `0x20..0x10` must be a non-empty range"),
+            ("0x0..0x10001:ro", "region `0x0..0x10001` must be a non-empty range"),
+            ("0x0..0x10000:ro", "region `0x0..0x10000` is too large"),
+            ("0x10..0x20:rw", "unknown protection `rw`"),
+        ];
+        for (region, expected) in cases {
+            let reason = invalid(&format!("run code.mc -p {region}"));
+            assert!(reason.starts_with(expected), "{region}: {reason}");
+        }
+    }
+
+    #[test]
+    fn devices_are_mapped_on_request() {
+        let console = |value| parse(&format!("run code.mc --console {value}")).unwrap().console;
+        assert_eq!(console("on"), Some(CONSOLE_BASE));
+        assert_eq!(console("off"), None);
+        assert_eq!(console("0xfe00"), Some(Value::new(0xfe00)));
+        let timer = parse("run code.mc -T on").unwrap().timer;
+        assert_eq!(timer, Some(TIMER_BASE));
+
+        let reason = invalid("run code.mc -c nowhere");
+        assert!(reason.starts_with("invalid console address `nowhere`"), "{reason}");
+    }
+
+    #[test]
+    fn memory_size_is_bounded() {
+        for size in ["2", "0x10000"] {
+            let memory = parse(&format!("run code.mc -m {size}")).unwrap().memory;
+            assert_eq!(memory, parse_number(size).unwrap());
+        }
+        for size in ["0", "1", "0x10001"] {
+            let reason = invalid(&format!("run code.mc --memory {size}"));
+            assert_eq!(reason, "memory size must be between 2 and 65536 bytes", "{size}");
+        }
+    }
+}
@@ -1,10 +1,14 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, fmt::Write, ops::Range, sync::Arc};
 
 use terl::{AsBuffer, Error, FileBuffer, MakeError, WithBufName, WithSpan};
 
 use crate::{
-    macros,
-    parser::{self, Ident, Stmt},
+    debug_info::{DebugInfo, Expansion, Location},
+    image::Image,
+    macros::{self, MacroCall},
+    parser::{self, BytesArg, DataKind, Expr, Ident, LayoutKind, Stmt},
+    symbols::{SymbolKind, Symbols},
+    Fault,
 };
 
 #[derive(Debug)]
@@ -14,47 +18,137 @@ struct Function {
     body: Vec<Stmt>,
 }
 
+// operands are resolved after layout, once the address of every label is known
 #[derive(Debug)]
-struct MacroCall {
-    called: macros::VirtualCall,
-    args: Vec<macros::Meta>,
+enum Command {
+    Command {
+        op: crate::Op,
+        called: Ident,
+        args: Vec<Expr>,
+    },
+    MacroCall {
+        called: macros::VirtualCall,
+        args: Vec<(Ident, Expr)>,
+    },
+    Label(Ident),
+    Data(parser::Data),
+    Layout(parser::Layout),
+    // the commands between `Enter` and its `Leave` are an inlined call
+    Enter(Ident),
+    Leave,
 }
 
-impl MacroCall {
-    fn call(&self, memory: &mut crate::Memory) -> Result<(), Error> {
-        (self.called)(memory, &self.args)
-    }
+// what names in an expression can refer to besides defines
+struct Env<'a> {
+    labels: &'a HashMap<Arc<str>, crate::Value>,
+    // address of the command or data the expression is part of
+    here: Option<crate::Value>,
+    // the cells of the constant pool by their value, once it is placed
+    pool: Option<&'a HashMap<u16, crate::Value>>,
 }
 
-#[derive(Debug)]
-enum Command {
-    Command(crate::Command),
-    MacroCall(MacroCall),
+/// The section commands and data are placed in until a `.section`.
+pub const CODE_SECTION: &str = "code";
+
+// addresses filled without a gap, started by a layout directive or the
+// first command of the program, or the cells of an `#alloc`
+struct Block {
+    // `None` for the cells of an `#alloc`
+    section: Option<Arc<str>>,
+    start: usize,
+    end: usize,
+    // the directive starting the block, the first thing placed in it or the
+    // name of the allocation
+    opened: Option<Ident>,
 }
 
-impl From<crate::Command> for Command {
-    fn from(v: crate::Command) -> Self {
-        Self::Command(v)
+impl Block {
+    fn describe(&self) -> String {
+        let (start, end) = (self.start, self.end);
+        match (&self.section, &self.opened) {
+            (Some(section), _) => format!("{start:#06x}..{end:#06x} of section {section}"),
+            (None, Some(name)) => format!("{start:#06x}..{end:#06x} allocated for {name}"),
+            (None, None) => format!("{start:#06x}..{end:#06x} allocated"),
+        }
     }
 }
 
-impl From<MacroCall> for Command {
-    fn from(v: MacroCall) -> Self {
-        Self::MacroCall(v)
+/// Where `#alloc` reserves cells unless `#alloc_region` moved it.
+pub const ALLOC_REGION: Range<u32> = 0x10..0x100;
+
+/// Cells reserved with `#alloc`.
+#[derive(Debug, Clone)]
+pub struct Allocation {
+    pub name: Arc<str>,
+    pub start: crate::Value,
+    /// Size in bytes.
+    pub len: u16,
+}
+
+/// The output of a [`Compiler`], ready to be loaded into a
+/// [`Machine`](crate::machine::Machine).
+#[derive(Debug, Clone)]
+pub struct Program {
+    /// Address the program starts running from, where the first bytes of
+    /// the code section were placed.
+    pub entry: crate::Value,
+    pub image: Image,
+    /// Macro calls to run before the command at their address.
+    pub macro_calls: Vec<(crate::Value, MacroCall)>,
+    /// Labels and the defines that evaluate to a value.
+    pub symbols: Symbols,
+    pub debug_info: DebugInfo,
+    /// Cells reserved with `#alloc`, by address.
+    pub allocations: Vec<Allocation>,
+}
+
+impl Program {
+    /// Lists the allocated cells and the placed bytes by address, one
+    /// `start..end  what` line each.
+    pub fn layout(&self) -> String {
+        let allocations = self.allocations.iter().map(|allocation| {
+            let start = *allocation.start as u32;
+            (start, start + allocation.len as u32, format!("alloc {}", allocation.name))
+        });
+        let segments = self.image.segments.iter().map(|segment| {
+            (*segment.origin as u32, segment.end() as u32, "image".to_owned())
+        });
+        let mut entries = allocations.chain(segments).collect::<Vec<_>>();
+        entries.sort_by_key(|(start, ..)| *start);
+
+        let mut layout = String::new();
+        for (start, end, what) in entries {
+            writeln!(layout, "{start:#06x}..{end:#06x}  {what}").unwrap();
+        }
+        layout
     }
 }
 
 #[derive(Debug, Default)]
 pub struct Compiler {
-    // for defines
-    defines: HashMap<Arc<str>, crate::Value>,
-    // for function
-    args: HashMap<Arc<str>, crate::Value>,
+    // for defines, evaluated where they are used
+    defines: HashMap<Arc<str>, parser::Define>,
+    // for function, arguments and local names of the innermost expansion,
+    // the expansions it is part of can not be seen from it
+    args: HashMap<Arc<str>, Expr>,
+    // number of expansions in progress
+    depth: usize,
+    // unique names made so far
+    gensyms: usize,
     functions: HashMap<Arc<str>, Arc<Function>>,
+    // for labels, their addresses are assigned in `Compiler::program`
+    labels: HashMap<Arc<str>, Ident>,
 
     files: HashMap<Arc<str>, Arc<FileBuffer>>,
+    // where the lines of each file start, to find the line of a span
+    line_starts: HashMap<Arc<str>, Vec<usize>>,
 
     commands: Vec<Command>,
+
+    // for `#alloc`, `None` for `ALLOC_REGION`
+    alloc_region: Option<Range<u32>>,
+    // sorted by address, with the name given to `#alloc`
+    allocations: Vec<(Allocation, Ident)>,
 }
 
 impl Compiler {
@@ -65,6 +159,9 @@ impl Compiler {
     pub fn compile_file(&mut self, buffer: Arc<FileBuffer>) -> Result<(), Error> {
         self.files
             .insert(buffer.buf_name().to_owned(), buffer.clone());
+        let chars: &[char] = buffer.as_ref().as_ref();
+        self.line_starts
+            .insert(buffer.buf_name().to_owned(), line_starts(chars));
         let mut parser = terl::Parser::new(buffer.clone());
 
         let items = parser::parse_items(&mut parser).map_err(|e| {
@@ -73,7 +170,6 @@ impl Compiler {
             error.extend(e.error().into_mesages());
             error
         })?;
-        dbg!(&items);
 
         for item in items {
             self.compile_item(item)?;
@@ -82,9 +178,54 @@ impl Compiler {
         Ok(())
     }
 
+    /// Compiles `source` as if it was read from a file called `name`.
+    pub fn compile_str(&mut self, name: &str, source: &str) -> Result<(), Error> {
+        let buffer = FileBuffer::new(name.into(), source.chars().collect());
+        self.compile_file(Arc::new(buffer))
+    }
+
     pub fn compile_define(&mut self, define: parser::Define) -> Result<(), Error> {
-        let value = self.redirect(&define.value)?;
-        self.defines.insert(define.name.literal().clone(), value);
+        if define.name.literal().parse::<crate::Op>().is_ok() {
+            return Err(define.name.make_error("define conflicts with a command"));
+        }
+        if let Some(function) = self.functions.get(define.name.literal()) {
+            let message = function.name.make_message("function defined here");
+            let err = define.name.make_error("define conflicts with a function");
+            return Err(err.append(message));
+        }
+        if let Some(label) = self.labels.get(define.name.literal()) {
+            let message = label.make_message("label defined here");
+            let err = define.name.make_error("define conflicts with a label");
+            return Err(err.append(message));
+        }
+        if let Some(exist) = self.defines.get(define.name.literal()) {
+            let message = exist.name.make_message("define already exists");
+            let err = define.name.make_error("define already exists");
+            return Err(err.append(message));
+        }
+
+        self.defines.insert(define.name.literal().clone(), define);
+        Ok(())
+    }
+
+    pub fn compile_label(&mut self, label: &parser::Label) -> Result<(), Error> {
+        let name = &label.name;
+        if let Some(exist) = self.labels.get(name.literal()) {
+            let message = exist.make_message("label already exists");
+            let err = name.make_error("label already exists");
+            return Err(err.append(message));
+        }
+        if self.defines.contains_key(name.literal()) {
+            return Err(name.make_error("label conflicts with a define"));
+        }
+        if let Some(function) = self.functions.get(name.literal()) {
+            let message = function.name.make_message("function defined here");
+            let err = name.make_error("label conflicts with a function");
+            return Err(err.append(message));
+        }
+
+        self.labels.insert(name.literal().clone(), name.clone());
+        self.commands.push(Command::Label(name.clone()));
         Ok(())
     }
 
@@ -94,6 +235,16 @@ impl Compiler {
             let err = function.name.make_error("function already exists");
             return Err(err.append(message));
         }
+        if let Some(define) = self.defines.get(function.name.literal()) {
+            let message = define.name.make_message("define given here");
+            let err = function.name.make_error("function conflicts with a define");
+            return Err(err.append(message));
+        }
+        if let Some(label) = self.labels.get(function.name.literal()) {
+            let message = label.make_message("label defined here");
+            let err = function.name.make_error("function conflicts with a label");
+            return Err(err.append(message));
+        }
 
         let name = function.name;
         let args = function.args;
@@ -107,17 +258,169 @@ impl Compiler {
         Ok(())
     }
 
-    pub fn redirect<'a>(&'a self, value: &'a Ident) -> Result<crate::Value, Error> {
-        self.args
-            .get(value.literal())
-            .or_else(|| self.defines.get(value.literal()))
-            .copied()
-            .map(Ok)
-            .unwrap_or_else(|| value.literal().parse::<crate::Value>())
-            .map_err(|e| {
-                let reason = format!("{}: {value}", e);
-                value.make_error(reason)
-            })
+    /// Replaces the names of arguments in `value` by what they are bound to.
+    fn bind(&self, value: &Expr) -> Expr {
+        match value {
+            Expr::Atom(atom) => self.args.get(atom.literal()).unwrap_or(value).clone(),
+            Expr::Unary { op, operand } => Expr::Unary {
+                op: op.clone(),
+                operand: Box::new(self.bind(operand)),
+            },
+            Expr::Binary { op, lhs, rhs } => Expr::Binary {
+                op: op.clone(),
+                lhs: Box::new(self.bind(lhs)),
+                rhs: Box::new(self.bind(rhs)),
+            },
+            Expr::Constant { marker, value } => Expr::Constant {
+                marker: marker.clone(),
+                value: Box::new(self.bind(value)),
+            },
+        }
+    }
+
+    /// Evaluates `value` in the current scope, labels are not available yet.
+    pub fn redirect(&self, value: &Expr) -> Result<crate::Value, Error> {
+        let labels = HashMap::new();
+        let env = Env {
+            labels: &labels,
+            here: None,
+            pool: None,
+        };
+        self.resolve(&self.bind(value), &env)
+    }
+
+    fn resolve(&self, value: &Expr, env: &Env) -> Result<crate::Value, Error> {
+        let value = self.evaluate(value, env, &mut Vec::new())?;
+        Ok(crate::Value::new(value as u16))
+    }
+
+    /// Evaluates a bound expression, every intermediate result has to fit
+    /// into 16 bits, either signed or unsigned.
+    fn evaluate(
+        &self,
+        value: &Expr,
+        env: &Env,
+        defines: &mut Vec<Arc<str>>,
+    ) -> Result<i64, Error> {
+        let (op, result) = match value {
+            Expr::Atom(atom) => return self.evaluate_atom(atom, env, defines),
+            Expr::Constant { marker, value } => {
+                let reason = "constants can only be used in commands and data";
+                let pool = env.pool.ok_or_else(|| marker.make_error(reason))?;
+                let value = self.evaluate(value, &Env { here: None, ..*env }, defines)?;
+                let reason = format!("constant ={value} has no cell in the pool");
+                let cell = pool.get(&(value as u16)).ok_or_else(|| marker.make_error(reason))?;
+                return Ok(**cell as i64);
+            }
+            Expr::Unary { op, operand } => {
+                let operand = self.evaluate(operand, env, defines)?;
+                let result = match op.literal().as_str() {
+                    "-" => -operand,
+                    _ => !operand & 0xffff,
+                };
+                (op, result)
+            }
+            Expr::Binary { op, lhs, rhs } => {
+                let lhs = self.evaluate(lhs, env, defines)?;
+                let rhs = self.evaluate(rhs, env, defines)?;
+                let (unsigned_lhs, unsigned_rhs) = (lhs & 0xffff, rhs & 0xffff);
+                let shift = |rhs: i64| u32::try_from(rhs).ok().filter(|rhs| *rhs < 16);
+                let result = match op.literal().as_str() {
+                    "+" => Some(lhs + rhs),
+                    "-" => Some(lhs - rhs),
+                    "*" => Some(lhs * rhs),
+                    "/" => lhs.checked_div(rhs),
+                    "%" => lhs.checked_rem(rhs),
+                    "&" => Some(unsigned_lhs & unsigned_rhs),
+                    "|" => Some(unsigned_lhs | unsigned_rhs),
+                    "^" => Some(unsigned_lhs ^ unsigned_rhs),
+                    "<<" => shift(rhs).map(|rhs| unsigned_lhs << rhs),
+                    _ => shift(rhs).map(|rhs| unsigned_lhs >> rhs),
+                };
+                let Some(result) = result else {
+                    let reason = format!("invalid right operand {rhs} of `{op}`");
+                    return Err(op.make_error(reason));
+                };
+                (op, result)
+            }
+        };
+
+        if !(-0x8000..=0xffff).contains(&result) {
+            let reason = format!("result {result} of `{op}` does not fit in 16 bits");
+            return Err(op.make_error(reason));
+        }
+        Ok(result)
+    }
+
+    // the constants in `value` and the defines it uses, with their markers
+    fn constants<'e>(
+        &'e self,
+        value: &'e Expr,
+        found: &mut Vec<(&'e Ident, &'e Expr)>,
+        defines: &mut Vec<Arc<str>>,
+    ) {
+        match value {
+            Expr::Atom(atom) => {
+                let name = atom.literal();
+                // a define referring to itself is reported when evaluated
+                if let Some(define) = self.defines.get(name).filter(|_| !defines.contains(name)) {
+                    defines.push(name.clone());
+                    self.constants(&define.value, found, defines);
+                    defines.pop();
+                }
+            }
+            Expr::Unary { operand, .. } => self.constants(operand, found, defines),
+            Expr::Binary { lhs, rhs, .. } => {
+                self.constants(lhs, found, defines);
+                self.constants(rhs, found, defines);
+            }
+            Expr::Constant { marker, value } => found.push((marker, value)),
+        }
+    }
+
+    fn evaluate_atom(
+        &self,
+        atom: &Ident,
+        env: &Env,
+        defines: &mut Vec<Arc<str>>,
+    ) -> Result<i64, Error> {
+        let name = atom.literal();
+        if name.as_str() == "." {
+            let reason = "`.` can only be used in commands and data";
+            return env
+                .here
+                .map(|here| *here as i64)
+                .ok_or_else(|| atom.make_error(reason));
+        }
+        if name.starts_with(|c: char| c.is_ascii_digit()) {
+            return name
+                .parse::<crate::Value>()
+                .map(|value| *value as i64)
+                .map_err(|e| atom.make_error(format!("{}: {atom}", e)));
+        }
+
+        if let Some(define) = self.defines.get(name) {
+            if defines.contains(name) {
+                return Err(atom.make_error(format!("define {atom} refers to itself")));
+            }
+            defines.push(name.clone());
+            let value = self.evaluate(&define.value, env, defines).map_err(|e| {
+                let message = atom.make_message(format!("while evaluating {atom}"));
+                e.append(message)
+            });
+            defines.pop();
+            return value;
+        }
+        if let Some(value) = env.labels.get(name) {
+            return Ok(**value as i64);
+        }
+
+        let reason = if self.labels.contains_key(name) {
+            format!("label {atom} can not be used here")
+        } else {
+            format!("undefined name {atom}")
+        };
+        Err(atom.make_error(reason))
     }
 
     pub fn compile_calling(&mut self, calling: &parser::Calling) -> Result<(), Error> {
@@ -132,14 +435,17 @@ impl Compiler {
 
         match calling.called.literal().as_str() {
             builtin if builtin.parse::<crate::Op>().is_ok() => {
-                if calling.args.len() != 2 {
-                    let reason = format!("{} call requires 2 arguments", builtin);
+                let op = builtin.parse::<crate::Op>().unwrap();
+                if calling.args.len() != op.operands() {
+                    let reason = format!("{} call requires {} arguments", builtin, op.operands());
                     return Err(Error::new(args_span, buf_name.to_owned(), reason));
                 }
-                let a = self.redirect(&calling.args[0])?;
-                let b = self.redirect(&calling.args[1])?;
-                self.commands
-                    .push(crate::Command::new(builtin.parse().unwrap(), a, b).into());
+                let args = calling.args.iter().map(|arg| self.bind(arg)).collect();
+                self.commands.push(Command::Command {
+                    op,
+                    called: calling.called.clone(),
+                    args,
+                });
             }
             _custom => {
                 let fn_called = &calling.called;
@@ -153,27 +459,32 @@ impl Compiler {
                         return Err(Error::new(args_span, buf_name.to_owned(), reason));
                     }
 
-                    let mut conflicts = HashMap::new();
-
-                    for (arg, literal) in function.args.iter().zip(calling.args.iter()) {
-                        let arg = arg.literal();
-                        let value = self.redirect(literal)?;
-                        if let Some(conflict) = self.args.insert(arg.clone(), value) {
-                            conflicts.insert(arg.clone(), conflict);
-                        }
+                    // arguments are bound in the caller's environment, the
+                    // body only sees them and its own local names
+                    let mut env = HashMap::new();
+                    for (arg, literal) in function.args.iter().zip(&calling.args) {
+                        env.insert(arg.literal().clone(), self.bind(literal));
                     }
-
+                    let caller = std::mem::replace(&mut self.args, env);
+                    self.depth += 1;
+                    // labels are local to the expansion, even before they are placed
                     for stmt in &function.body {
-                        self.compile_stmt(stmt)?;
+                        if let Stmt::Label(label) = stmt {
+                            let unique = self.gensym(&label.name);
+                            self.args.insert(label.name.literal().clone(), Expr::Atom(unique));
+                        }
                     }
 
-                    for arg in &function.args {
-                        self.args.remove(arg.literal());
-                    }
+                    self.commands.push(Command::Enter(fn_called.clone()));
+                    let compiled = function
+                        .body
+                        .iter()
+                        .try_for_each(|stmt| self.compile_stmt(stmt));
+                    self.commands.push(Command::Leave);
 
-                    for (arg, value) in conflicts {
-                        self.args.insert(arg, value);
-                    }
+                    self.depth -= 1;
+                    self.args = caller;
+                    compiled?;
                 } else {
                     let reason = format!("undefinded function {}", fn_called.literal());
                     return Err(Error::new(args_span, buf_name.to_owned(), reason));
@@ -184,6 +495,36 @@ impl Compiler {
         Ok(())
     }
 
+    // a name based on `name` that can not be written in source, where names
+    // end at a `:`
+    fn gensym(&mut self, name: &Ident) -> Ident {
+        self.gensyms += 1;
+        name.with_literal(&format!("{name}:{}", self.gensyms))
+    }
+
+    /// Binds each of `names` to a unique name for the rest of the function
+    /// body being expanded, so labels and allocations made with them do not
+    /// clash between expansions.
+    pub fn compile_gensym(&mut self, called: &Ident, names: &[Ident]) -> Result<(), Error> {
+        if self.depth == 0 {
+            return Err(called.make_error("unique names can only be made in function bodies"));
+        }
+        for name in names {
+            let unique = self.gensym(name);
+            self.args.insert(name.literal().clone(), Expr::Atom(unique));
+        }
+        Ok(())
+    }
+
+    /// The name `name` stands for in the current expansion, like a label
+    /// local to it or a name made by `#gensym`.
+    pub fn local_name(&self, name: &Ident) -> Ident {
+        match self.args.get(name.literal()) {
+            Some(Expr::Atom(local)) => local.clone(),
+            _ => name.clone(),
+        }
+    }
+
     pub fn compile_macro(&mut self, r#macro: &parser::Macro) -> Result<(), Error> {
         let Some(macro_) = macros::MACROS
             .get(r#macro.called.literal().as_str())
@@ -194,39 +535,187 @@ impl Compiler {
         };
 
         match macro_ {
-            macros::Macro::Preprocess(preprocess) => preprocess(self, &r#macro.args),
+            macros::Macro::Preprocess(preprocess) => {
+                preprocess(self, &r#macro.called, &r#macro.args)
+            }
             macros::Macro::Fn(vf) => {
-                let make_meta = |arg: &Ident| {
-                    let val = self.redirect(arg).ok();
-                    let id = arg.to_owned();
-                    macros::Meta { id, val }
-                };
-                let metas = r#macro.args.iter().map(make_meta).collect();
-                let call = MacroCall {
-                    called: vf,
-                    args: metas,
+                let bind = |arg: &Ident| (arg.clone(), self.bind(&Expr::Atom(arg.clone())));
+                let args = r#macro.args.iter().map(bind).collect();
+                self.commands
+                    .push(Command::MacroCall { called: vf, args });
+
+                Ok(())
+            }
+        }
+    }
+
+    pub fn compile_data(&mut self, data: parser::Data) -> Result<(), Error> {
+        self.commands.push(Command::Data(data));
+        Ok(())
+    }
+
+    // the number of bytes `data` takes when laid out at `here`, only labels
+    // placed before it are known
+    fn data_len(&self, data: &parser::Data, env: &Env) -> Result<usize, Error> {
+        let here = env.here.map_or(0, |here| *here as usize);
+        let len = match &data.kind {
+            DataKind::Words(words) => words.len() * 2,
+            DataKind::Bytes(args) => args
+                .iter()
+                .map(|arg| match arg {
+                    BytesArg::Text(text) => text.len(),
+                    BytesArg::Value(_) => 1,
+                })
+                .sum(),
+            DataKind::Zero(len) => *self.resolve(len, env)? as usize,
+            DataKind::Align(align) => match *self.resolve(align, env)? as usize {
+                0 => return Err(align.make_error("alignment must not be 0")),
+                align => (align - here % align) % align,
+            },
+        };
+        Ok(len)
+    }
+
+    // the bytes of `data`, which is laid out at `env.here`
+    fn data_bytes(&self, data: &parser::Data, len: usize, env: &Env) -> Result<Vec<u8>, Error> {
+        let mut bytes = Vec::with_capacity(len);
+        match &data.kind {
+            DataKind::Words(words) => {
+                for word in words {
+                    bytes.extend(self.resolve(word, env)?.to_le_bytes());
+                }
+            }
+            DataKind::Bytes(args) => {
+                for arg in args {
+                    match arg {
+                        BytesArg::Text(text) => bytes.extend(text),
+                        BytesArg::Value(value) => {
+                            let byte = self.evaluate(value, env, &mut Vec::new())?;
+                            if !(-0x80..=0xff).contains(&byte) {
+                                let reason = format!("{byte} does not fit in a byte");
+                                return Err(value.make_error(reason));
+                            }
+                            bytes.push(byte as u8);
+                        }
+                    }
+                }
+            }
+            DataKind::Zero(_) | DataKind::Align(_) => bytes.resize(len, 0),
+        }
+        Ok(bytes)
+    }
+
+    pub fn compile_layout(&mut self, layout: parser::Layout) -> Result<(), Error> {
+        self.commands.push(Command::Layout(layout));
+        Ok(())
+    }
+
+    // the address `layout` continues at, `sections` holds where every
+    // section left off
+    fn layout_start(
+        &self,
+        layout: &parser::Layout,
+        sections: &HashMap<Arc<str>, usize>,
+        env: &Env,
+    ) -> Result<usize, Error> {
+        let origin = match &layout.kind {
+            LayoutKind::Org(addr) => addr,
+            LayoutKind::Section {
+                origin: Some(origin),
+                ..
+            } => origin,
+            LayoutKind::Section { name, origin: None } => {
+                return match sections.get(name.literal()) {
+                    Some(pc_val) => Ok(*pc_val),
+                    None => Err(name.make_error(format!("new section {name} needs an origin"))),
                 };
+            }
+        };
+        Ok(*self.resolve(origin, env)? as usize)
+    }
 
-                self.commands.push(call.into());
+    /// Makes `#alloc` reserve cells between `start` and `end`, cells
+    /// allocated before stay where they are.
+    pub fn set_alloc_region(&mut self, start: &Ident, end: &Ident) -> Result<(), Error> {
+        let value = |ident: &Ident| self.redirect(&Expr::Atom(ident.clone()));
+        let region = *value(start)? as u32..*value(end)? as u32;
+        if region.is_empty() {
+            let reason = format!("region {:#06x}..{:#06x} is empty", region.start, region.end);
+            return Err(end.make_error(reason));
+        }
+        self.alloc_region = Some(region);
+        Ok(())
+    }
 
-                Ok(())
+    /// Reserves `words` cells that no other allocation uses and binds their
+    /// address to the define `name`.
+    pub fn allocate(&mut self, name: &Ident, words: &Ident) -> Result<(), Error> {
+        let len = *self.redirect(&Expr::Atom(words.clone()))? as u32 * 2;
+        if len == 0 || len > 0xffff {
+            return Err(words.make_error(format!("can not allocate {words} words")));
+        }
+        let region = self.alloc_region.clone().unwrap_or(ALLOC_REGION);
+
+        // the first gap between the allocations that is large enough
+        let mut start = region.start;
+        for (taken, _) in &self.allocations {
+            let taken = *taken.start as u32..*taken.start as u32 + taken.len as u32;
+            if taken.end <= start {
+                continue;
+            }
+            if start + len <= taken.start {
+                break;
             }
+            start = taken.end;
+        }
+        if start + len > region.end {
+            let (first, last) = (region.start, region.end);
+            let reason = format!("no room for {words} words in {first:#06x}..{last:#06x}");
+            return Err(name.make_error(reason));
         }
+
+        let value = Expr::Atom(name.with_literal(&format!("{start:#06x}")));
+        self.compile_define(parser::Define {
+            name: name.clone(),
+            value,
+        })?;
+        let allocation = Allocation {
+            name: name.literal().clone(),
+            start: crate::Value::new(start as u16),
+            len: len as u16,
+        };
+        let idx = self.allocations.partition_point(|(taken, _)| *taken.start < start as u16);
+        self.allocations.insert(idx, (allocation, name.clone()));
+        Ok(())
     }
 
     pub fn compile_stmt(&mut self, stmt: &parser::Stmt) -> Result<(), Error> {
         match stmt {
             Stmt::Calling(calling) => self.compile_calling(calling),
             Stmt::Macro(r#macro) => self.compile_macro(r#macro),
+            Stmt::Label(label) => self.compile_label(&parser::Label {
+                name: self.local_name(&label.name),
+            }),
         }
     }
 
     pub fn compile_item(&mut self, item: parser::Item) -> Result<(), Error> {
         match item {
             parser::Item::Define(define) => self.compile_define(define)?,
+            parser::Item::DefineOrCalling(calling) => {
+                let name = calling.called.literal();
+                if name.parse::<crate::Op>().is_ok() || self.functions.contains_key(name) {
+                    self.compile_calling(&calling)?
+                } else {
+                    self.compile_define(calling.into_define())?
+                }
+            }
             parser::Item::Function(function) => self.compile_function(function)?,
             parser::Item::Calling(calling) => self.compile_calling(&calling)?,
             parser::Item::Macro(r#macro) => self.compile_macro(&r#macro)?,
+            parser::Item::Label(label) => self.compile_label(&label)?,
+            parser::Item::Data(data) => self.compile_data(data)?,
+            parser::Item::Layout(layout) => self.compile_layout(layout)?,
         }
         Ok(())
     }
@@ -246,42 +735,692 @@ impl Compiler {
         Ok(output)
     }
 
-    pub fn commands(&self) -> impl Iterator<Item = &crate::Command> {
-        self.commands.iter().filter_map(|c| match c {
-            Command::Command(c) => Some(c),
-            Command::MacroCall(_) => None,
-        })
+    /// Renders a `fault` of a program compiled by this compiler at the
+    /// command that caused it, followed by the calls it was inlined from.
+    pub fn handle_fault(&self, fault: &Fault, debug_info: &DebugInfo) -> Result<String, String> {
+        let location = fault
+            .pc
+            .and_then(|pc| Some((pc, debug_info.location_at(pc)?)));
+        let Some((pc, location)) = location else {
+            return Ok(format!("{fault}\n"));
+        };
+
+        let mut error = Error::new(location.span, location.file.clone(), fault.to_string());
+        for expansion in debug_info.stack_at(pc).iter().rev() {
+            let call_site = &expansion.call_site;
+            let reason = format!("in {} called here", expansion.function);
+            let message = Error::new(call_site.span, call_site.file.clone(), reason);
+            error.extend(message.into_mesages());
+        }
+        self.handle_error(&error)
+    }
+
+    // where `ident` was written, with the line of the buffer it came from
+    fn location(&self, ident: &Ident) -> Location {
+        let span = ident.get_span();
+        let line = self.line_starts.get(ident.path()).map_or(0, |starts| {
+            // the lines starting at or before the span, the first always does
+            starts.partition_point(|start| *start <= span.start)
+        });
+        Location {
+            file: ident.path().clone(),
+            span,
+            line,
+        }
     }
 
-    pub fn run(&mut self, pc_val: crate::Value, memory: &mut crate::Memory) {
-        let mut macros = HashMap::new();
-        self.commands
-            .iter_mut()
-            .fold(pc_val, |pc_val, command| match command {
-                Command::Command(c) => {
-                    c.encode(&mut memory[pc_val.0 as usize..]);
-                    pc_val.next_command().unwrap()
+    /// Encodes the compiled commands and data into a flat binary, laid out
+    /// from `origin`, and returns it with the address it has to be loaded
+    /// at.
+    pub fn assemble(&self, origin: crate::Value) -> Result<(crate::Value, Vec<u8>), Error> {
+        self.program(origin).map(|program| program.image.flatten())
+    }
+
+    /// Lays the compiled commands and data out starting at `origin` and
+    /// resolves their operands.
+    pub fn program(&self, origin: crate::Value) -> Result<Program, Error> {
+        // first pass: assign an address to every label, size all data and
+        // place the blocks of every section
+        let mut labels = HashMap::new();
+        let mut data_lens = Vec::new();
+        let mut layout_starts = Vec::new();
+        let mut section: Arc<str> = CODE_SECTION.into();
+        let mut sections = HashMap::new();
+        let mut blocks = vec![Block {
+            section: Some(section.clone()),
+            start: *origin as usize,
+            end: *origin as usize,
+            opened: None,
+        }];
+        let mut pc_val = *origin as usize;
+        for command in &self.commands {
+            let env = Env {
+                labels: &labels,
+                here: Some(crate::Value::new(pc_val as u16)),
+                pool: None,
+            };
+            let (len, placed, what) = match command {
+                Command::Command { called, .. } => (crate::Command::SIZE, called, "command"),
+                Command::Data(data) => {
+                    let len = self.data_len(data, &env)?;
+                    data_lens.push(len);
+                    (len, &data.directive, "data")
                 }
-                Command::MacroCall(m) => {
-                    macros.entry(pc_val).or_insert_with(Vec::new).push(m);
-                    pc_val
+                Command::Layout(layout) => {
+                    sections.insert(section.clone(), pc_val);
+                    pc_val = self.layout_start(layout, &sections, &env)?;
+                    layout_starts.push(crate::Value::new(pc_val as u16));
+                    if let LayoutKind::Section { name, .. } = &layout.kind {
+                        section = name.literal().clone();
+                    }
+                    blocks.push(Block {
+                        section: Some(section.clone()),
+                        start: pc_val,
+                        end: pc_val,
+                        opened: Some(layout.directive.clone()),
+                    });
+                    continue;
                 }
-            });
+                Command::MacroCall { .. } | Command::Enter(_) | Command::Leave => continue,
+                Command::Label(name) => {
+                    labels.insert(name.literal().clone(), crate::Value::new(pc_val as u16));
+                    continue;
+                }
+            };
+            pc_val += len;
+            if pc_val > 0x10000 {
+                let reason = format!("{what} does not fit in the address space");
+                return Err(placed.make_error(reason));
+            }
+            let block = blocks.last_mut().expect("a block is always open");
+            block.end = pc_val;
+            block.opened.get_or_insert_with(|| placed.clone());
+        }
 
-        let mut pc_val = pc_val;
-        loop {
-            memory.write(0x00.into(), pc_val);
-            if let Some(macros) = macros.get(&pc_val) {
-                for m in macros {
-                    m.call(memory).unwrap();
+        // the constant pool follows the code section, a cell for each value
+        let mut pool = HashMap::new();
+        let mut pool_values = Vec::new();
+        let pool_start = match *section == *CODE_SECTION {
+            true => pc_val,
+            false => sections[CODE_SECTION],
+        };
+        let mut pool_marker = None;
+        let env = Env {
+            labels: &labels,
+            here: None,
+            pool: None,
+        };
+        for command in &self.commands {
+            let values: Vec<&Expr> = match command {
+                Command::Command { args, .. } => args.iter().collect(),
+                Command::Data(parser::Data {
+                    kind: DataKind::Words(words),
+                    ..
+                }) => words.iter().collect(),
+                Command::Data(parser::Data {
+                    kind: DataKind::Bytes(args),
+                    ..
+                }) => args
+                    .iter()
+                    .filter_map(|arg| match arg {
+                        BytesArg::Value(value) => Some(value),
+                        BytesArg::Text(_) => None,
+                    })
+                    .collect(),
+                _ => Vec::new(),
+            };
+            let mut found = Vec::new();
+            for value in values {
+                self.constants(value, &mut found, &mut Vec::new());
+            }
+            for (marker, value) in found {
+                let value = self.resolve(value, &env)?;
+                if pool.contains_key(&*value) {
+                    continue;
                 }
+                let cell = pool_start + pool_values.len() * 2;
+                if cell + 2 > 0x10000 {
+                    let reason = "constant pool does not fit in the address space";
+                    return Err(marker.make_error(reason));
+                }
+                pool.insert(*value, crate::Value::new(cell as u16));
+                pool_values.push(value);
+                pool_marker.get_or_insert(marker);
             }
-            if memory.eval(0x00.into()).is_err() {
-                println!("Aborted");
-                break;
+        }
+        blocks.push(Block {
+            section: Some(CODE_SECTION.into()),
+            start: pool_start,
+            end: pool_start + pool_values.len() * 2,
+            opened: pool_marker.cloned(),
+        });
+
+        // the program starts where the code section got its first bytes
+        let entry = blocks
+            .iter()
+            .find(|block| block.section.as_deref() == Some(CODE_SECTION) && block.start < block.end)
+            .map_or(origin, |block| crate::Value::new(block.start as u16));
+
+        // blocks may not share any address, not even with allocated cells
+        blocks.extend(self.allocations.iter().map(|(allocation, name)| Block {
+            section: None,
+            start: *allocation.start as usize,
+            end: *allocation.start as usize + allocation.len as usize,
+            opened: Some(name.clone()),
+        }));
+        let mut blocks = blocks
+            .into_iter()
+            .filter(|block| block.start < block.end)
+            .collect::<Vec<_>>();
+        blocks.sort_by_key(|block| block.start);
+        for pair in blocks.windows(2) {
+            let (earlier, later) = (&pair[0], &pair[1]);
+            if later.start < earlier.end {
+                let opened = |block: &Block| block.opened.clone().expect("the block has bytes");
+                let reason = format!("{} overlaps {}", later.describe(), earlier.describe());
+                let message = format!("{} placed here", earlier.describe());
+                let err = opened(later).make_error(reason);
+                return Err(err.append(opened(earlier).make_message(message)));
             }
+        }
+
+        // second pass: resolve operands against defines and labels
+        let mut image = Image::new();
+        // the bytes of the block being filled, and where it starts
+        let mut bytes = Vec::new();
+        let mut start = origin;
+        let mut data_lens = data_lens.into_iter();
+        let mut layout_starts = layout_starts.into_iter();
+        let mut macro_calls = Vec::new();
+        let mut debug_info = DebugInfo::new();
+        // calls being expanded, with their first address
+        let mut calls = Vec::new();
+        let mut pc_val = origin;
+        for command in &self.commands {
+            match command {
+                Command::Command { op, called, args } => {
+                    debug_info.lines.push((pc_val, self.location(called)));
+                    let env = Env {
+                        labels: &labels,
+                        here: Some(pc_val),
+                        pool: Some(&pool),
+                    };
+                    let mut operands = [crate::Value::new(0); 2];
+                    for (operand, arg) in operands.iter_mut().zip(args) {
+                        *operand = self.resolve(arg, &env)?;
+                    }
+                    let mut encoded = [0; crate::Command::SIZE];
+                    crate::Command::new(*op, operands[0], operands[1]).encode(&mut encoded);
+                    bytes.extend(encoded);
+                    pc_val = crate::Value::new(pc_val.wrapping_add(crate::Command::SIZE as u16));
+                }
+                Command::Data(data) => {
+                    let env = Env {
+                        labels: &labels,
+                        here: Some(pc_val),
+                        pool: Some(&pool),
+                    };
+                    let len = data_lens.next().expect("every data was sized");
+                    bytes.extend(self.data_bytes(data, len, &env)?);
+                    pc_val = crate::Value::new(pc_val.wrapping_add(len as u16));
+                }
+                Command::MacroCall { called, args } => {
+                    let env = Env {
+                        labels: &labels,
+                        here: Some(pc_val),
+                        pool: Some(&pool),
+                    };
+                    let make_meta = |(id, arg): &(Ident, Expr)| {
+                        let val = self.resolve(arg, &env).ok();
+                        let id = id.to_owned();
+                        macros::Meta { id, val }
+                    };
+                    let call = MacroCall {
+                        called: *called,
+                        args: args.iter().map(make_meta).collect(),
+                    };
+                    macro_calls.push((pc_val, call));
+                }
+                Command::Layout(_) => {
+                    let bytes = std::mem::take(&mut bytes);
+                    if !bytes.is_empty() {
+                        image.push(start, bytes);
+                    }
+                    start = layout_starts.next().expect("every layout was placed");
+                    pc_val = start;
+                }
+                Command::Label(_) => {}
+                Command::Enter(called) => calls.push((called, pc_val)),
+                Command::Leave => {
+                    // unbalanced after a call failed to compile
+                    if let Some((called, start)) = calls.pop() {
+                        debug_info.expansions.push(Expansion {
+                            function: called.literal().clone(),
+                            call_site: self.location(called),
+                            start,
+                            end: pc_val,
+                        });
+                    }
+                }
+            }
+        }
+        if !bytes.is_empty() {
+            image.push(start, bytes);
+        }
+        if !pool_values.is_empty() {
+            let bytes = pool_values.iter().flat_map(|value| value.to_le_bytes());
+            image.push(crate::Value::new(pool_start as u16), bytes.collect());
+        }
+        // sections place the commands out of order
+        debug_info.lines.sort_by_key(|(addr, _)| **addr);
+
+        let mut symbols = Symbols::new();
+        for (name, value) in &labels {
+            symbols.insert(name.clone(), *value, SymbolKind::Label);
+        }
+        let env = Env {
+            labels: &labels,
+            here: None,
+            pool: Some(&pool),
+        };
+        for (name, define) in &self.defines {
+            // defines using `.` have no single value
+            if let Ok(value) = self.resolve(&define.value, &env) {
+                symbols.insert(name.clone(), value, SymbolKind::Define);
+            }
+        }
+        // constants are named like they are written
+        for (value, cell) in &pool {
+            symbols.insert(format!("={value:#06x}").into(), *cell, SymbolKind::Define);
+        }
+
+        let allocations = self.allocations.iter().map(|(allocation, _)| allocation.clone());
+        Ok(Program {
+            entry,
+            image,
+            macro_calls,
+            symbols,
+            debug_info,
+            allocations: allocations.collect(),
+        })
+    }
+}
+
+// the index of the first char of every line in `chars`
+fn line_starts(chars: &[char]) -> Vec<usize> {
+    let newlines = chars.iter().enumerate().filter(|(_, c)| **c == '\n');
+    std::iter::once(0).chain(newlines.map(|(idx, _)| idx + 1)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{machine::Machine, RunOutcome};
+
+    // compiles `source` at 0, errors are rendered
+    fn program(source: &str) -> Result<Program, String> {
+        let mut compiler = Compiler::new();
+        let program = compiler
+            .compile_str("test.mc", source)
+            .and_then(|()| compiler.program(crate::Value::new(0)));
+        program.map_err(|e| compiler.handle_error(&e).unwrap_or_else(|e| e))
+    }
+
+    fn value(program: &Program, name: &str) -> u16 {
+        *program.symbols.get(name).expect("the define has a value").value
+    }
+
+    // compiles `source` after `pre.mc`, with the program at 0xf000
+    fn program_with_pre(source: &str) -> (Compiler, Program) {
+        let source = format!("#include pre.mc\n{source}");
+        let mut compiler = Compiler::new();
+        let program = compiler
+            .compile_str("test.mc", &source)
+            .and_then(|()| compiler.program(crate::Value::new(0xf000)));
+        let program = program.unwrap_or_else(|e| panic!("{}", compiler.handle_error(&e).unwrap()));
+        (compiler, program)
+    }
+
+    // runs `source` after `pre.mc` until it halts
+    fn run_with_pre(source: &str) -> (Machine, Program) {
+        let (_, program) = program_with_pre(source);
+        let mut machine = Machine::new(0x10000);
+        machine.load_program(&program).unwrap();
+        assert_eq!(machine.run(Some(1000)), RunOutcome::Halted);
+        (machine, program)
+    }
+
+    fn cell(machine: &Machine, program: &Program, name: &str) -> u16 {
+        let addr = crate::Value::new(value(program, name));
+        *machine.memory().read(addr).unwrap()
+    }
+
+    #[test]
+    fn operator_precedence() {
+        let source = "\
+A = 1 + 2 * 3
+B = (1 + 2) * 3
+C = 10 - 4 - 3
+D = 1 << 2 + 1
+E = 1 | 6 & 3
+F = 7 ^ 2 | 8
+G = ~0x00ff & 0xfff0
+H = -1
+";
+        let program = program(source).unwrap();
+        let expected = [
+            ("A", 7),
+            ("B", 9),
+            ("C", 3),
+            ("D", 8),
+            ("E", 3),
+            ("F", 13),
+            ("G", 0xff00),
+            ("H", 0xffff),
+        ];
+        for (name, expected) in expected {
+            assert_eq!(value(&program, name), expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn overflow_is_an_error() {
+        for value in ["0xffff + 1", "0x8000 * 2", "-0x8000 - 1"] {
+            let err = program(&format!("X = {value}\nSET 0 X\n")).unwrap_err();
+            assert!(err.contains("does not fit in 16 bits"), "{value}: {err}");
+        }
+        for value in ["1 << 16", "1 / 0"] {
+            let err = program(&format!("X = {value}\nSET 0 X\n")).unwrap_err();
+            assert!(err.contains("invalid right operand"), "{value}: {err}");
+        }
+    }
+
+    #[test]
+    fn jne_keeps_the_registers() {
+        for (cond, taken) in [(0, 1), (1, 0)] {
+            let source = format!(
+                "\
+SET D1 {cond}
+SET D2 7
+SET D3 9
+jne D1 jumped
+HLT
+jumped:
+SET D4 1
+HLT
+"
+            );
+            let (machine, program) = run_with_pre(&source);
+            let cell = |name| cell(&machine, &program, name);
+            assert_eq!(cell("D4"), taken, "cond {cond}");
+            assert_eq!((cell("D1"), cell("D2"), cell("D3")), (cond, 7, 9), "cond {cond}");
+        }
+    }
+
+    // the operand `b` of the command at `addr`
+    fn operand_b(program: &Program, addr: usize) -> u16 {
+        let (origin, bytes) = program.image.flatten();
+        let start = addr - *origin as usize;
+        u16::from_le_bytes([bytes[start + 3], bytes[start + 4]])
+    }
+
+    #[test]
+    fn function_labels_are_local() {
+        let source = "\
+spin =
+	loop:
+	SET 0 loop
+spin
+spin
+HLT
+";
+        let program = program(source).unwrap();
+        assert_eq!((operand_b(&program, 0), operand_b(&program, 5)), (0, 5));
+    }
+
+    #[test]
+    fn functions_do_not_see_the_names_of_their_callers() {
+        let source = "\
+jump_done =
+	SET 0 done
+outer =
+	jump_done
+	done:
+	SET 2 1
+outer
+done:
+HLT
+";
+        let program = program(source).unwrap();
+        // the global `done`, not the one local to `outer` at 5
+        assert_eq!(operand_b(&program, 0), 10);
+    }
+
+    #[test]
+    fn name_equals_value_is_a_define_unless_called() {
+        let source = "\
+X =1+2*3
+Y=7
+Z = 8
+set_two x =
+	SET 2 x
+set_two =5
+";
+        let program = program(source).unwrap();
+        assert_eq!((value(&program, "X"), value(&program, "Y")), (7, 7));
+        assert_eq!(value(&program, "Z"), 8);
+        // the call points at the pool cell after it
+        assert_eq!(operand_b(&program, 0), 5);
+        assert_eq!(value(&program, "=0x0005"), 5);
+    }
+
+    #[test]
+    fn overlapping_blocks_are_reported() {
+        let err = program("SET 2 1\nSET 2 2\n.org 5\nHLT\n").unwrap_err();
+        let reason = "0x0005..0x000a of section code overlaps 0x0000..0x000a of section code";
+        assert!(err.contains(reason), "{err}");
+        assert!(err.contains("0x0000..0x000a of section code placed here"), "{err}");
+
+        let source = "\
+.section data 0x10
+.word 1 2
+.section more 0x12
+HLT
+";
+        let err = program(source).unwrap_err();
+        let reason = "0x0012..0x0017 of section more overlaps 0x0010..0x0014 of section data";
+        assert!(err.contains(reason), "{err}");
+        assert!(err.contains("0x0010..0x0014 of section data placed here"), "{err}");
+    }
+
+    #[test]
+    fn entry_is_the_start_of_the_code() {
+        let source = "\
+.section data 0x100
+.word 1
+.section code 0x200
+HLT
+";
+        assert_eq!(*program(source).unwrap().entry, 0x200);
+    }
+
+    #[test]
+    fn alloc_may_not_overlap_placed_bytes() {
+        let err = program("#alloc_region 0x00 0x10\n#alloc X\nHLT\n").unwrap_err();
+        let reason = "0x0000..0x0002 allocated for X overlaps 0x0000..0x0005 of section code";
+        assert!(err.contains(reason), "{err}");
+    }
+
+    #[test]
+    fn alloc_fills_gaps_in_its_region() {
+        let source = "\
+#alloc_region 0x14 0x18
+#alloc A
+#alloc_region 0x10 0x18
+#alloc B 2
+#alloc C
+";
+        let err = program(&format!("{source}#alloc D 2\n")).unwrap_err();
+        assert!(err.contains("no room for 2 words in 0x0010..0x0018"), "{err}");
+
+        let program = program(source).unwrap();
+        let addrs = ["A", "B", "C"].map(|name| value(&program, name));
+        assert_eq!(addrs, [0x14, 0x10, 0x16]);
+    }
+
+    #[test]
+    fn pool_holds_each_constant_once_after_the_code() {
+        let source = "\
+SET 2 =5
+SET 4 =5
+.section data 0x100
+.word =7
+.section code
+SET 6 =7
+";
+        let program = program(source).unwrap();
+        // the code takes 0x00..0x0f, the pool follows it
+        let operands = [0, 5, 10].map(|addr| operand_b(&program, addr));
+        assert_eq!(operands, [0x0f, 0x0f, 0x11]);
+        let (_, bytes) = program.image.flatten();
+        assert_eq!(bytes[0x0f..0x13], [5, 0, 7, 0]);
+        assert_eq!(bytes[0x100..0x102], [0x11, 0]);
+    }
+
+    #[test]
+    fn labels_can_be_used_before_they_are_placed() {
+        let program = program("SET 2 end\nSET 4 .\nend:\nHLT\n").unwrap();
+        assert_eq!((operand_b(&program, 0), operand_b(&program, 5)), (10, 5));
+        assert_eq!(*program.symbols.get("end").unwrap().value, 10);
+    }
+
+    #[test]
+    fn data_takes_its_size() {
+        let source = "\
+.word 1 0x0302
+.bytes \"ab\" 3
+.align 4
+.zero 3
+end:
+HLT
+";
+        let program = program(source).unwrap();
+        // 4 bytes of words, 3 bytes, 1 to align and 3 zeros
+        assert_eq!(*program.symbols.get("end").unwrap().value, 11);
+        let (_, bytes) = program.image.flatten();
+        assert_eq!(bytes[..11], [1, 0, 2, 3, b'a', b'b', 3, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn data_sizes_can_not_use_later_labels() {
+        for directive in [".zero", ".align"] {
+            let err = program(&format!("{directive} end\nend:\nHLT\n")).unwrap_err();
+            assert!(err.contains("label end can not be used here"), "{directive}: {err}");
+        }
+    }
+
+    #[test]
+    fn line_starts_follow_the_newlines() {
+        let chars = "a\nbc\n\nd".chars().collect::<Vec<_>>();
+        assert_eq!(line_starts(&chars), [0, 2, 5, 6]);
+        assert_eq!(line_starts(&[]), [0]);
+    }
+
+    // the line of `pre.mc` holding `text`
+    fn pre_line(text: &str) -> usize {
+        let pre = std::fs::read_to_string("pre.mc").unwrap();
+        pre.lines().position(|line| line.contains(text)).expect("pre.mc has it") + 1
+    }
+
+    #[test]
+    fn commands_are_described_with_their_calls() {
+        let (_, program) = program_with_pre("SET D1 1\nadd D1 D2\nnot D1\nHLT\n");
+        let debug_info = &program.debug_info;
+        let start = |function: &str| {
+            let expansion = debug_info.expansions.iter().find(|e| &*e.function == function);
+            expansion.expect("the function was called").start
+        };
+
+        let add = debug_info.describe(start("add")).unwrap();
+        let line = pre_line("SET D1 0xFFFF");
+        assert_eq!(add, format!("pre.mc:{line} in add called from test.mc:3"));
+
+        // `not` starts with a call of `mov`
+        let not = debug_info.describe(start("not")).unwrap();
+        let (line, call) = (pre_line("STR a TO_CP"), pre_line("mov a D1"));
+        let expected = format!("pre.mc:{line} in mov called from pre.mc:{call}");
+        assert_eq!(not, expected + " in not called from test.mc:4");
+
+        let hlt = debug_info.lines.last().unwrap().0;
+        assert_eq!(debug_info.describe(hlt).unwrap(), "test.mc:5");
+    }
+
+    #[test]
+    fn faults_are_shown_where_they_happened() {
+        let source = "\
+load a b =
+	LOD a b
+SET D2 0xffff
+load D1 D2
+HLT
+";
+        let (compiler, program) = program_with_pre(source);
+        let mut machine = Machine::new(0x10000);
+        machine.load_program(&program).unwrap();
+        let RunOutcome::Fault(fault) = machine.run(Some(1000)) else {
+            panic!("reading 0xffff faults");
+        };
+
+        let rendered = compiler.handle_fault(&fault, machine.debug_info()).unwrap();
+        let at = rendered.find(&fault.to_string()).expect("the fault is shown");
+        let called = rendered.find("in load called here").expect("the call is shown");
+        assert!(at < called, "{rendered}");
+
+        // a fault outside of a machine has no command to point at
+        let fault = Fault::new(crate::FaultKind::ReadOutOfBounds, crate::Value::new(0xffff));
+        let rendered = compiler.handle_fault(&fault, machine.debug_info()).unwrap();
+        assert_eq!(rendered, format!("{fault}\n"));
+    }
+
+    #[test]
+    fn gensym_names_differ_between_calls() {
+        let source = "\
+keep x =
+	#gensym tmp
+	#alloc tmp
+	SET tmp x
+keep 1
+keep 2
+HLT
+";
+        let (machine, program) = run_with_pre(source);
+        let mut cells = program
+            .symbols
+            .iter()
+            .filter(|symbol| symbol.name.starts_with("tmp:"))
+            .map(|symbol| *machine.memory().read(symbol.value).unwrap())
+            .collect::<Vec<_>>();
+        cells.sort();
+        assert_eq!(cells, [1, 2]);
+        assert!(program.symbols.get("tmp").is_none());
+    }
+
+    #[test]
+    fn gensym_is_refused_outside_of_functions() {
+        let err = program("#gensym tmp\nHLT\n").unwrap_err();
+        assert!(err.contains("unique names can only be made in function bodies"), "{err}");
+    }
 
-            pc_val = pc_val.next_command().unwrap();
+    #[test]
+    fn functions_do_not_share_names_with_defines_or_labels() {
+        let cases = [
+            ("X = 1\nX a =\n\tSET a 1\nHLT\n", "function conflicts with a define"),
+            ("X:\nHLT\nX a =\n\tSET a 1\n", "function conflicts with a label"),
+            ("X a =\n\tSET a 1\nX:\nHLT\n", "label conflicts with a function"),
+        ];
+        for (source, expected) in cases {
+            let err = program(source).unwrap_err();
+            assert!(err.contains(expected), "{source:?}: {err}");
         }
     }
 }
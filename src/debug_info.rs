@@ -0,0 +1,94 @@
+use std::{cmp::Reverse, fmt::Write, sync::Arc};
+
+use terl::Span;
+
+use crate::Value;
+
+/// A place in a source file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub file: Arc<str>,
+    pub span: Span,
+    /// The line `span` starts on, counted from 1.
+    pub line: usize,
+}
+
+impl core::fmt::Display for Location {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/// The commands a call of a function was inlined into.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expansion {
+    pub function: Arc<str>,
+    /// Where the function was called.
+    pub call_site: Location,
+    pub start: Value,
+    /// One past the last command of the expansion.
+    pub end: Value,
+}
+
+impl Expansion {
+    pub fn contains(&self, addr: Value) -> bool {
+        (*self.start..*self.end).contains(&*addr)
+    }
+}
+
+/// What a debugger needs to know about a compiled program besides its
+/// symbols.
+#[derive(Debug, Clone, Default)]
+pub struct DebugInfo {
+    /// Every inlined call, an expansion is listed after the ones it contains.
+    pub expansions: Vec<Expansion>,
+    /// The source of every command, ordered by address.
+    pub lines: Vec<(Value, Location)>,
+}
+
+impl DebugInfo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The expansions `addr` lies in, outermost first.
+    pub fn stack_at(&self, addr: Value) -> Vec<&Expansion> {
+        let mut stack = self
+            .expansions
+            .iter()
+            .enumerate()
+            .filter(|(_, expansion)| expansion.contains(addr))
+            .collect::<Vec<_>>();
+        // nested expansions are never larger than the ones around them, and
+        // come first when they cover the same commands
+        stack.sort_by_key(|(idx, expansion)| {
+            (*expansion.start, Reverse(*expansion.end), Reverse(*idx))
+        });
+        stack.into_iter().map(|(_, expansion)| expansion).collect()
+    }
+
+    /// How many inlined calls `addr` lies in.
+    pub fn depth_at(&self, addr: Value) -> usize {
+        self.expansions
+            .iter()
+            .filter(|expansion| expansion.contains(addr))
+            .count()
+    }
+
+    /// Where the command at `addr` was written.
+    pub fn location_at(&self, addr: Value) -> Option<&Location> {
+        let idx = self.lines.binary_search_by_key(&*addr, |(at, _)| **at).ok()?;
+        Some(&self.lines[idx].1)
+    }
+
+    /// Describes where the command at `addr` came from, like
+    /// `pre.mc:42 in add called from code.mc:5`.
+    pub fn describe(&self, addr: Value) -> Option<String> {
+        let mut description = self.location_at(addr)?.to_string();
+        for expansion in self.stack_at(addr).iter().rev() {
+            let (function, call_site) = (&expansion.function, &expansion.call_site);
+            write!(description, " in {function} called from {call_site}").unwrap();
+        }
+        Some(description)
+    }
+}
@@ -0,0 +1,642 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
+    io::{BufRead, Write},
+    ops::ControlFlow,
+};
+
+use crate::{
+    disasm,
+    machine::Machine,
+    snapshot::Snapshot,
+    symbols::{SymbolKind, Symbols},
+    RunOutcome, Value,
+};
+
+/// Steps the debugger keeps to step back through.
+pub const HISTORY: usize = 100_000;
+
+/// Defines shown by the `regs` command, if the program has them.
+pub const REGISTERS: [&str; 7] = ["PC", "D1", "D2", "D3", "D4", "SP", "CP"];
+
+pub const HELP: &str = "\
+commands:
+    s, step [n]            execute one (or n) commands
+    n, next                step over inlined function calls
+    c, continue            run until a breakpoint, a watchpoint or the end
+    rs, rstep [n]          take back one (or n) commands
+    rc, rcontinue          run backwards until a breakpoint, a watchpoint or
+                           the oldest kept step, devices are not taken back
+    b, break <addr>        stop before executing the command at <addr>
+    w, watch <addr>        stop after the cell at <addr> changes
+    d, delete <addr>       remove a breakpoint or watchpoint
+    i, info                list breakpoints and watchpoints
+    r, regs                show the registers defined by the program
+    x <addr> [n]           show n cells starting at <addr> (default: 8)
+    set <addr> <value>     write <value> into the cell at <addr>
+    l, list [n]            show the next n commands (default: 5)
+    save [path]            keep the machine state, or write it to <path>
+    restore [path]         go back to the kept state, or the one in <path>
+    reset                  restart the program from its entry
+    q, quit                leave the debugger
+addresses and values are numbers or names of labels and defines
+";
+
+/// An interactive debugger driving a [`Machine`].
+#[derive(Debug)]
+pub struct Debugger {
+    machine: Machine,
+    symbols: Symbols,
+    breakpoints: BTreeSet<Value>,
+    // watched cells with their values when execution last stopped
+    watchpoints: BTreeMap<Value, Option<Value>>,
+    // set once the machine stopped, stepping is refused until a reset
+    stopped: Option<RunOutcome>,
+    // the state kept by `save` without a path
+    checkpoint: Option<Snapshot>,
+}
+
+// why stepping stopped without the machine stopping
+enum Stop {
+    Breakpoint,
+    Watchpoint(Value, Option<Value>, Option<Value>),
+    // stepping back ran out of history
+    HistoryStart,
+}
+
+impl Debugger {
+    /// Creates a debugger for `machine`, source locations are taken from its
+    /// [`Machine::debug_info`]. The machine keeps the last [`HISTORY`] steps.
+    pub fn new(mut machine: Machine, symbols: Symbols) -> Self {
+        machine.set_history(Some(HISTORY));
+        Debugger {
+            machine,
+            symbols,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+            stopped: None,
+            checkpoint: None,
+        }
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    pub fn into_machine(self) -> Machine {
+        self.machine
+    }
+
+    /// Reads commands from `input` until it ends or `quit` is entered,
+    /// writing a prompt and the results to `output`.
+    pub fn repl(
+        &mut self,
+        mut input: impl BufRead,
+        mut output: impl Write,
+    ) -> std::io::Result<()> {
+        write!(output, "{}", self.location())?;
+        loop {
+            write!(output, "(mcdb) ")?;
+            output.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            match self.execute(line.trim()) {
+                ControlFlow::Continue(text) => write!(output, "{text}")?,
+                ControlFlow::Break(()) => return Ok(()),
+            }
+        }
+    }
+
+    /// Executes one command line and returns what it prints, or breaks on
+    /// `quit`.
+    pub fn execute(&mut self, line: &str) -> ControlFlow<(), String> {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return ControlFlow::Continue(String::new());
+        };
+        let args = words.collect::<Vec<_>>();
+        let result = match command {
+            "s" | "step" => self.step(&args),
+            "n" | "next" => self.next(),
+            "c" | "continue" => self.cont(),
+            "rs" | "rstep" => self.rstep(&args),
+            "rc" | "rcontinue" => self.rcont(),
+            "b" | "break" => self.add_breakpoint(&args),
+            "w" | "watch" => self.add_watchpoint(&args),
+            "d" | "delete" => self.delete(&args),
+            "i" | "info" => Ok(self.info()),
+            "r" | "regs" => Ok(self.regs()),
+            "x" => self.dump(&args),
+            "set" => self.set(&args),
+            "l" | "list" => self.list(&args),
+            "save" => self.save(&args),
+            "restore" => self.restore(&args),
+            "reset" => {
+                self.machine.reset();
+                self.stopped = None;
+                self.update_watchpoints();
+                Ok(self.location())
+            }
+            "q" | "quit" => return ControlFlow::Break(()),
+            "h" | "help" => Ok(HELP.to_owned()),
+            other => Err(format!("unknown command `{other}`, try `help`")),
+        };
+        ControlFlow::Continue(result.unwrap_or_else(|e| format!("error: {e}\n")))
+    }
+
+    // parses a number or the name of a symbol
+    fn value(&self, arg: &str) -> Result<Value, String> {
+        if let Some(symbol) = self.symbols.get(arg) {
+            return Ok(symbol.value);
+        }
+        arg.parse()
+            .map_err(|_| format!("`{arg}` is neither a number nor a known name"))
+    }
+
+    fn one_addr(&self, args: &[&str]) -> Result<Value, String> {
+        match args {
+            [addr] => self.value(addr),
+            _ => Err("expect one address".to_owned()),
+        }
+    }
+
+    // `addr` with the name of a label or define at it
+    fn name(&self, addr: Value) -> String {
+        match self.symbols.name_of(addr, &[SymbolKind::Label, SymbolKind::Define]) {
+            Some(name) => format!("{addr} ({name})"),
+            None => addr.to_string(),
+        }
+    }
+
+    // the command at the program counter, with where it was written
+    fn location(&self) -> String {
+        let pc = self.machine.pc();
+        let command = match self.machine.memory().fetch(pc) {
+            Ok(command) => disasm::format_command(&command, &self.symbols),
+            Err(_) => "<invalid>".to_owned(),
+        };
+        let mut location = format!("{}: {command}", self.name(pc));
+        if let Some(source) = self.machine.debug_info().describe(pc) {
+            write!(location, " at {source}").unwrap();
+        }
+        location + "\n"
+    }
+
+    fn update_watchpoints(&mut self) {
+        for (addr, value) in self.watchpoints.iter_mut() {
+            *value = self.machine.memory().peek(*addr);
+        }
+    }
+
+    // a watchpoint whose cell changed since it was last updated
+    fn changed_watchpoint(&self) -> Option<Stop> {
+        self.watchpoints.iter().find_map(|(addr, old)| {
+            let new = self.machine.memory().peek(*addr);
+            (new != *old).then_some(Stop::Watchpoint(*addr, *old, new))
+        })
+    }
+
+    // executes one command, `Break` holds the reason to stop
+    fn step_once(&mut self) -> ControlFlow<Option<Stop>> {
+        if let ControlFlow::Break(outcome) = self.machine.step() {
+            self.stopped = Some(outcome);
+            return ControlFlow::Break(None);
+        }
+        if let Some(stop) = self.changed_watchpoint() {
+            return ControlFlow::Break(Some(stop));
+        }
+        if self.breakpoints.contains(&self.machine.pc()) {
+            return ControlFlow::Break(Some(Stop::Breakpoint));
+        }
+        ControlFlow::Continue(())
+    }
+
+    // takes back one command, `Break` holds the reason to stop
+    fn back_once(&mut self) -> ControlFlow<Option<Stop>> {
+        if !self.machine.step_back() {
+            return ControlFlow::Break(Some(Stop::HistoryStart));
+        }
+        self.stopped = None;
+        if let Some(stop) = self.changed_watchpoint() {
+            return ControlFlow::Break(Some(stop));
+        }
+        if self.breakpoints.contains(&self.machine.pc()) {
+            return ControlFlow::Break(Some(Stop::Breakpoint));
+        }
+        ControlFlow::Continue(())
+    }
+
+    // steps, or steps back, while `more` holds for the machine, then reports
+    // where it stopped
+    fn step_while(
+        &mut self,
+        back: bool,
+        mut more: impl FnMut(&Machine) -> bool,
+    ) -> Result<String, String> {
+        if let (Some(outcome), false) = (self.stopped, back) {
+            return Err(format!("program stopped: {outcome}, `reset` to restart"));
+        }
+        let stop = loop {
+            let flow = match back {
+                true => self.back_once(),
+                false => self.step_once(),
+            };
+            match flow {
+                ControlFlow::Break(stop) => break stop,
+                ControlFlow::Continue(()) if more(&self.machine) => {}
+                ControlFlow::Continue(()) => break None,
+            }
+        };
+
+        let mut report = String::new();
+        match stop {
+            Some(Stop::Breakpoint) => report += "breakpoint\n",
+            Some(Stop::HistoryStart) => report += "no older steps kept\n",
+            Some(Stop::Watchpoint(addr, old, new)) => {
+                let cell = |value: Option<Value>| match value {
+                    Some(value) => value.to_string(),
+                    None => "-".to_owned(),
+                };
+                let (name, old, new) = (self.name(addr), cell(old), cell(new));
+                writeln!(report, "watchpoint {name}: {old} -> {new}").unwrap();
+            }
+            None => {}
+        }
+        self.update_watchpoints();
+        if let Some(outcome) = self.stopped {
+            write!(report, "program stopped: {outcome}").unwrap();
+            let source = outcome.pc().and_then(|pc| self.machine.debug_info().describe(pc));
+            if let Some(source) = source {
+                write!(report, " at {source}").unwrap();
+            }
+            return Ok(report + "\n");
+        }
+        Ok(report + &self.location())
+    }
+
+    fn step(&mut self, args: &[&str]) -> Result<String, String> {
+        let mut count = step_count(args)?;
+        self.step_while(false, |_| {
+            count = count.saturating_sub(1);
+            count > 0
+        })
+    }
+
+    fn rstep(&mut self, args: &[&str]) -> Result<String, String> {
+        let mut count = step_count(args)?;
+        self.step_while(true, |_| {
+            count = count.saturating_sub(1);
+            count > 0
+        })
+    }
+
+    // steps until execution leaves the inlined calls starting at the program
+    // counter, like stepping over a call
+    fn next(&mut self) -> Result<String, String> {
+        let depth = call_depth(&self.machine);
+        self.step_while(false, |machine| call_depth(machine) > depth)
+    }
+
+    fn cont(&mut self) -> Result<String, String> {
+        self.step_while(false, |_| true)
+    }
+
+    fn rcont(&mut self) -> Result<String, String> {
+        self.step_while(true, |_| true)
+    }
+
+    fn add_breakpoint(&mut self, args: &[&str]) -> Result<String, String> {
+        let addr = self.one_addr(args)?;
+        self.breakpoints.insert(addr);
+        Ok(format!("breakpoint at {}\n", self.name(addr)))
+    }
+
+    fn add_watchpoint(&mut self, args: &[&str]) -> Result<String, String> {
+        let addr = self.one_addr(args)?;
+        self.watchpoints.insert(addr, self.machine.memory().peek(addr));
+        Ok(format!("watchpoint at {}\n", self.name(addr)))
+    }
+
+    fn delete(&mut self, args: &[&str]) -> Result<String, String> {
+        let addr = self.one_addr(args)?;
+        let breakpoint = self.breakpoints.remove(&addr);
+        let watchpoint = self.watchpoints.remove(&addr).is_some();
+        if !breakpoint && !watchpoint {
+            return Err(format!("nothing set at {}", self.name(addr)));
+        }
+        Ok(format!("deleted {}\n", self.name(addr)))
+    }
+
+    fn info(&self) -> String {
+        let mut info = String::new();
+        for addr in &self.breakpoints {
+            writeln!(info, "breakpoint {}", self.name(*addr)).unwrap();
+        }
+        for addr in self.watchpoints.keys() {
+            writeln!(info, "watchpoint {}", self.name(*addr)).unwrap();
+        }
+        info
+    }
+
+    fn regs(&self) -> String {
+        let mut regs = String::new();
+        for name in REGISTERS {
+            let Some(symbol) = self.symbols.get(name) else {
+                continue;
+            };
+            if let Some(value) = self.machine.memory().peek(symbol.value) {
+                writeln!(regs, "{name:<3} [{}] {value}", symbol.value).unwrap();
+            }
+        }
+        if regs.is_empty() {
+            // without defines only the program counter is known
+            writeln!(regs, "PC  [{}] {}", crate::machine::PC, self.machine.pc()).unwrap();
+        }
+        regs
+    }
+
+    fn dump(&self, args: &[&str]) -> Result<String, String> {
+        let (addr, count) = match args {
+            [addr] => (self.value(addr)?, 8),
+            [addr, count] => {
+                let count = count
+                    .parse::<u16>()
+                    .map_err(|e| format!("invalid count `{count}`: {e}"))?;
+                (self.value(addr)?, count)
+            }
+            _ => return Err("expect an address and an optional count".to_owned()),
+        };
+
+        let mut dump = String::new();
+        for idx in 0..count {
+            let cell = Value::new(addr.wrapping_add(idx.wrapping_mul(2)));
+            let Some(value) = self.machine.memory().peek(cell) else {
+                break;
+            };
+            writeln!(dump, "{}: {value}", self.name(cell)).unwrap();
+        }
+        Ok(dump)
+    }
+
+    fn set(&mut self, args: &[&str]) -> Result<String, String> {
+        let [addr, value] = args else {
+            return Err("expect an address and a value".to_owned());
+        };
+        let (addr, value) = (self.value(addr)?, self.value(value)?);
+        self.machine
+            .memory_mut()
+            .write(addr, value)
+            .map_err(|fault| fault.to_string())?;
+        self.update_watchpoints();
+        Ok(format!("{}: {value}\n", self.name(addr)))
+    }
+
+    fn list(&self, args: &[&str]) -> Result<String, String> {
+        let count = match args {
+            [] => 5,
+            [count] => count
+                .parse::<usize>()
+                .map_err(|e| format!("invalid count `{count}`: {e}"))?,
+            _ => return Err("expect at most one count".to_owned()),
+        };
+        let pc = *self.machine.pc() as usize;
+        let memory = self.machine.memory();
+        let len = count.saturating_mul(crate::Command::SIZE);
+        let end = memory.len().min(pc.saturating_add(len));
+        let bytes = memory.get(pc..end).unwrap_or_default();
+        let lines = disasm::decode(self.machine.pc(), bytes);
+        Ok(disasm::listing(&lines, &self.symbols))
+    }
+
+    fn save(&mut self, args: &[&str]) -> Result<String, String> {
+        let snapshot = self.machine.snapshot();
+        match args {
+            [] => {
+                self.checkpoint = Some(snapshot);
+                Ok(format!("saved at cycle {}\n", self.machine.cycles()))
+            }
+            [path] => {
+                std::fs::write(path, snapshot.encode())
+                    .map_err(|e| format!("failed to write file `{path}`: {e}"))?;
+                Ok(format!("saved to `{path}` at cycle {}\n", self.machine.cycles()))
+            }
+            _ => Err("expect at most one path".to_owned()),
+        }
+    }
+
+    fn restore(&mut self, args: &[&str]) -> Result<String, String> {
+        let snapshot = match args {
+            [] => self.checkpoint.clone().ok_or("nothing was saved")?,
+            [path] => {
+                let bytes = std::fs::read(path)
+                    .map_err(|e| format!("failed to read file `{path}`: {e}"))?;
+                Snapshot::decode(&bytes).map_err(|e| format!("{path}: {e}"))?
+            }
+            _ => return Err("expect at most one path".to_owned()),
+        };
+        self.machine.restore(&snapshot)?;
+        self.stopped = None;
+        self.update_watchpoints();
+        Ok(format!("restored cycle {}\n", self.machine.cycles()) + &self.location())
+    }
+}
+
+// the inlined calls the program counter is inside of, a call starting at it
+// has not been entered yet
+fn call_depth(machine: &Machine) -> usize {
+    let pc = machine.pc();
+    let stack = machine.debug_info().stack_at(pc);
+    stack.iter().filter(|expansion| expansion.start != pc).count()
+}
+
+// the optional count of `step` and `rstep`
+fn step_count(args: &[&str]) -> Result<usize, String> {
+    match args {
+        [] => Ok(1),
+        [count] => count
+            .parse::<usize>()
+            .map_err(|e| format!("invalid count `{count}`: {e}")),
+        _ => Err("expect at most one count".to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::Compiler, Command};
+
+    const SOURCE: &str = "\
+#include pre.mc
+#alloc X
+#alloc Y
+SET X 1
+SET Y 2
+start:
+add X Y
+copy:
+mov X Y
+end:
+HLT
+";
+
+    // a debugger for `SOURCE` at 0xf000
+    fn debugger() -> Debugger {
+        let mut compiler = Compiler::new();
+        compiler.compile_str("test.mc", SOURCE).unwrap();
+        let program = compiler.program(Value::new(0xf000)).unwrap();
+        let mut machine = Machine::new(0x10000);
+        machine.load_program(&program).unwrap();
+        Debugger::new(machine, program.symbols)
+    }
+
+    fn run(debugger: &mut Debugger, line: &str) -> String {
+        match debugger.execute(line) {
+            ControlFlow::Continue(text) => text,
+            ControlFlow::Break(()) => panic!("`{line}` left the debugger"),
+        }
+    }
+
+    fn addr(debugger: &Debugger, name: &str) -> Value {
+        debugger.symbols.get(name).expect("the program names it").value
+    }
+
+    fn cell(debugger: &Debugger, name: &str) -> u16 {
+        *debugger.machine().memory().read(addr(debugger, name)).unwrap()
+    }
+
+    #[test]
+    fn continue_stops_at_breakpoints() {
+        let mut debugger = debugger();
+        let start = addr(&debugger, "start");
+        let set = run(&mut debugger, "break start");
+        assert_eq!(set, format!("breakpoint at {start} (start)\n"));
+        assert_eq!(run(&mut debugger, "info"), format!("breakpoint {start} (start)\n"));
+
+        let report = run(&mut debugger, "continue");
+        assert!(report.starts_with(&format!("breakpoint\n{start} (start): ")), "{report}");
+        assert!(report.contains(" in add called from test.mc:7\n"), "{report}");
+        assert_eq!(debugger.machine().pc(), start);
+
+        assert_eq!(run(&mut debugger, "delete start"), format!("deleted {start} (start)\n"));
+        assert_eq!(run(&mut debugger, "continue"), "program stopped: halted\n");
+    }
+
+    #[test]
+    fn watchpoints_stop_after_the_cell_changes() {
+        let mut debugger = debugger();
+        let y = addr(&debugger, "Y");
+        assert_eq!(run(&mut debugger, "watch Y"), format!("watchpoint at {y} (Y)\n"));
+
+        let report = run(&mut debugger, "continue");
+        let start = addr(&debugger, "start");
+        let expected = format!("watchpoint {y} (Y): 0x0000 -> 0x0002\n{start} (start): ");
+        assert!(report.starts_with(&expected), "{report}");
+
+        let report = run(&mut debugger, "continue");
+        let end = addr(&debugger, "end");
+        let expected = format!("watchpoint {y} (Y): 0x0002 -> 0x0003\n{end} (end): HLT");
+        assert_eq!(report, expected + " at test.mc:11\n");
+    }
+
+    #[test]
+    fn step_takes_a_count() {
+        let mut debugger = debugger();
+        run(&mut debugger, "step 4");
+        assert_eq!(debugger.machine().cycles(), 4);
+        assert_eq!(*debugger.machine().pc(), 0xf000 + 4 * Command::SIZE as u16);
+        assert_eq!((cell(&debugger, "X"), cell(&debugger, "Y")), (1, 0));
+
+        let report = run(&mut debugger, "step x");
+        assert!(report.starts_with("error: invalid count `x`"), "{report}");
+        assert_eq!(debugger.machine().cycles(), 4);
+    }
+
+    #[test]
+    fn next_steps_over_inlined_calls() {
+        let mut debugger = debugger();
+        run(&mut debugger, "break start");
+        run(&mut debugger, "continue");
+
+        // `mov` follows `add` directly, `next` stops before entering it
+        let report = run(&mut debugger, "next");
+        assert_eq!(debugger.machine().pc(), addr(&debugger, "copy"), "{report}");
+        assert!(report.contains(" in mov called from test.mc:9\n"), "{report}");
+        assert_eq!(cell(&debugger, "X"), 3);
+
+        run(&mut debugger, "next");
+        assert_eq!(debugger.machine().pc(), addr(&debugger, "end"));
+        assert_eq!(cell(&debugger, "Y"), 3);
+    }
+
+    #[test]
+    fn x_shows_and_set_changes_cells() {
+        let mut debugger = debugger();
+        run(&mut debugger, "step 5");
+        let (x, y) = (addr(&debugger, "X"), addr(&debugger, "Y"));
+        assert_eq!(run(&mut debugger, "x X 1"), format!("{x} (X): 0x0001\n"));
+
+        assert_eq!(run(&mut debugger, "set Y 7"), format!("{y} (Y): 0x0007\n"));
+        assert_eq!(run(&mut debugger, "x Y 1"), format!("{y} (Y): 0x0007\n"));
+        run(&mut debugger, "continue");
+        assert_eq!(cell(&debugger, "X"), 8);
+
+        let report = run(&mut debugger, "set Y nowhere");
+        assert_eq!(report, "error: `nowhere` is neither a number nor a known name\n");
+    }
+
+    #[test]
+    fn regs_shows_the_defined_registers() {
+        let mut debugger = debugger();
+        run(&mut debugger, "step");
+        let regs = run(&mut debugger, "regs");
+        assert!(regs.starts_with("PC  [0x0000] 0xf005\n"), "{regs}");
+        let sp = addr(&debugger, "SP");
+        assert!(regs.contains(&format!("SP  [{sp}] 0xe000\n")), "{regs}");
+    }
+
+    #[test]
+    fn restore_goes_back_to_the_saved_state() {
+        let mut debugger = debugger();
+        assert_eq!(run(&mut debugger, "restore"), "error: nothing was saved\n");
+
+        run(&mut debugger, "step 5");
+        assert_eq!(run(&mut debugger, "save"), "saved at cycle 5\n");
+        run(&mut debugger, "continue");
+        assert_eq!(cell(&debugger, "Y"), 3);
+
+        let report = run(&mut debugger, "restore");
+        assert!(report.starts_with("restored cycle 5\n"), "{report}");
+        assert_eq!((cell(&debugger, "X"), cell(&debugger, "Y")), (1, 2));
+        // restoring takes back the stop as well
+        assert_eq!(run(&mut debugger, "continue"), "program stopped: halted\n");
+    }
+
+    #[test]
+    fn stepping_is_refused_after_the_program_stopped() {
+        let mut debugger = debugger();
+        assert_eq!(run(&mut debugger, "continue"), "program stopped: halted\n");
+        let refused = "error: program stopped: halted, `reset` to restart\n";
+        for line in ["step", "next", "continue"] {
+            assert_eq!(run(&mut debugger, line), refused, "{line}");
+        }
+
+        run(&mut debugger, "reset");
+        assert_eq!(debugger.machine().pc(), Value::new(0xf000));
+        assert_eq!(cell(&debugger, "Y"), 0);
+        let report = run(&mut debugger, "step");
+        assert!(!report.starts_with("error"), "{report}");
+        assert!(debugger.execute("quit").is_break());
+    }
+
+    #[test]
+    fn list_stops_at_the_end_of_the_memory() {
+        let mut debugger = debugger();
+        let listing = run(&mut debugger, "list 2");
+        assert_eq!(listing.lines().count(), 2, "{listing}");
+
+        let listing = run(&mut debugger, &format!("list {}", usize::MAX));
+        assert!(listing.lines().count() > 2, "{listing}");
+    }
+}
@@ -0,0 +1,290 @@
+use std::{
+    io::{Read, Write},
+    sync::{
+        mpsc::{self, Receiver},
+        Arc, Mutex,
+    },
+};
+
+use crate::{bus::Device, Value};
+
+/// Where the console is mapped unless configured otherwise.
+pub const CONSOLE_BASE: Value = Value::new(0xff00);
+/// Size of the console window in bytes.
+pub const CONSOLE_SIZE: u16 = 6;
+
+/// Offset of the console's output register.
+pub const CONSOLE_OUTPUT: u16 = 0;
+/// Offset of the console's input register.
+pub const CONSOLE_INPUT: u16 = 2;
+/// Offset of the console's status register.
+pub const CONSOLE_STATUS: u16 = 4;
+
+/// Status bit set while input is waiting.
+pub const INPUT_READY: u16 = 1 << 0;
+/// Status bit set while output can be written, always.
+pub const OUTPUT_READY: u16 = 1 << 1;
+
+/// A memory-mapped character console, like a minimal UART.
+///
+/// Its window holds three cells:
+/// - [`CONSOLE_OUTPUT`]: writing sends the low byte to the output.
+/// - [`CONSOLE_INPUT`]: reading waits for the next input byte, 0 once the
+///   input ended.
+/// - [`CONSOLE_STATUS`]: holds [`INPUT_READY`] and [`OUTPUT_READY`], reading
+///   it never waits.
+///
+/// The input is read on its own thread, so the status can tell whether a byte
+/// arrived without blocking the machine.
+#[derive(Debug)]
+pub struct Console<W> {
+    input: Receiver<u8>,
+    output: W,
+    // a byte taken from the input to answer a status read
+    pending: Option<u8>,
+}
+
+impl<W: Write> Console<W> {
+    pub fn new(input: impl Read + Send + 'static, output: W) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            // a failing input is treated like one that ended
+            for byte in input.bytes().map_while(Result::ok) {
+                if sender.send(byte).is_err() {
+                    break;
+                }
+            }
+        });
+        Console {
+            input: receiver,
+            output,
+            pending: None,
+        }
+    }
+
+    // takes the next input byte if one arrived, `wait`s for it if asked to,
+    // `None` once the input ended
+    fn fill(&mut self, wait: bool) -> Option<u8> {
+        if self.pending.is_none() {
+            self.pending = match wait {
+                true => self.input.recv().ok(),
+                false => self.input.try_recv().ok(),
+            };
+        }
+        self.pending
+    }
+}
+
+impl Console<std::io::Stdout> {
+    /// A console reading the host's stdin and writing to its stdout.
+    pub fn host() -> Self {
+        Console::new(std::io::stdin(), std::io::stdout())
+    }
+}
+
+impl<W: Write + std::fmt::Debug> Device for Console<W> {
+    fn read(&mut self, offset: u16) -> Value {
+        let value = match offset {
+            CONSOLE_INPUT => self.fill(true).map_or(0, u16::from),
+            CONSOLE_STATUS => self.fill(false).map_or(0, |_| INPUT_READY) | OUTPUT_READY,
+            _ => 0,
+        };
+        if offset == CONSOLE_INPUT {
+            self.pending = None;
+        }
+        Value::new(value)
+    }
+
+    fn write(&mut self, offset: u16, value: Value) {
+        if offset == CONSOLE_OUTPUT {
+            // the console has no way to report a failing output
+            let _ = self.output.write_all(&[*value as u8]);
+            let _ = self.output.flush();
+        }
+    }
+
+    // the input itself can not be saved, only a byte already taken from it
+    fn save(&self) -> Vec<u8> {
+        self.pending.into_iter().collect()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), String> {
+        self.pending = match state {
+            [] => None,
+            [byte] => Some(*byte),
+            _ => return Err("invalid console state".to_owned()),
+        };
+        Ok(())
+    }
+}
+
+/// Where the timer is mapped unless configured otherwise.
+pub const TIMER_BASE: Value = Value::new(0xff08);
+/// Size of the timer window in bytes.
+pub const TIMER_SIZE: u16 = 4;
+
+/// Offset of the timer's period register.
+pub const TIMER_PERIOD: u16 = 0;
+/// Offset of the timer's count register.
+pub const TIMER_COUNT: u16 = 2;
+
+/// A timer raising an interrupt every `period` executed commands.
+///
+/// Writing [`TIMER_PERIOD`] restarts the count, a period of 0 stops the
+/// timer. [`TIMER_COUNT`] holds the commands left until the next interrupt.
+#[derive(Debug, Clone, Default)]
+pub struct Timer {
+    period: u16,
+    count: u16,
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Device for Timer {
+    fn read(&mut self, offset: u16) -> Value {
+        match offset {
+            TIMER_PERIOD => Value::new(self.period),
+            TIMER_COUNT => Value::new(self.count),
+            _ => Value::new(0),
+        }
+    }
+
+    fn write(&mut self, offset: u16, value: Value) {
+        match offset {
+            TIMER_PERIOD => {
+                self.period = *value;
+                self.count = *value;
+            }
+            TIMER_COUNT => self.count = *value,
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self) -> bool {
+        if self.period == 0 {
+            return false;
+        }
+        self.count = self.count.saturating_sub(1);
+        if self.count == 0 {
+            self.count = self.period;
+            return true;
+        }
+        false
+    }
+
+    fn reset(&mut self) {
+        *self = Timer::new();
+    }
+
+    fn save(&self) -> Vec<u8> {
+        [self.period.to_le_bytes(), self.count.to_le_bytes()].concat()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), String> {
+        let &[p0, p1, c0, c1] = state else {
+            return Err("invalid timer state".to_owned());
+        };
+        self.period = u16::from_le_bytes([p0, p1]);
+        self.count = u16::from_le_bytes([c0, c1]);
+        Ok(())
+    }
+}
+
+/// An in-memory input or output for a [`Console`], clones share the same
+/// bytes so they can be inspected while the console owns one. A console
+/// stops reading an input buffer once it is empty.
+#[derive(Debug, Clone, Default)]
+pub struct Buffer {
+    bytes: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Buffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A buffer holding `bytes` to be read.
+    pub fn with_bytes(bytes: &[u8]) -> Self {
+        Buffer {
+            bytes: Arc::new(Mutex::new(bytes.to_vec())),
+        }
+    }
+
+    /// The bytes written and not yet read.
+    pub fn contents(&self) -> Vec<u8> {
+        self.bytes.lock().unwrap().clone()
+    }
+}
+
+impl Read for Buffer {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut bytes = self.bytes.lock().unwrap();
+        let len = buf.len().min(bytes.len());
+        buf[..len].copy_from_slice(&bytes[..len]);
+        bytes.drain(..len);
+        Ok(len)
+    }
+}
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.bytes.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bus::Bus, compiler::Compiler, machine::Machine, RunOutcome};
+
+    #[test]
+    fn console_echoes_its_input() {
+        let output = Buffer::new();
+        let console = Console::new(Buffer::with_bytes(b"hi"), output.clone());
+        let mut machine = Machine::new(0x10000);
+        let memory = machine.memory_mut();
+        memory.map(CONSOLE_BASE, CONSOLE_SIZE, Box::new(console)).unwrap();
+
+        // the input arrives on its own thread, the first byte has to be there
+        // for the program to see it
+        let status = Value::new(*CONSOLE_BASE + CONSOLE_STATUS);
+        while *Bus::read(memory, status).unwrap() & INPUT_READY == 0 {
+            std::thread::yield_now();
+        }
+
+        let source = "\
+#include pre.mc
+mov UART_STATUS D1
+getc D2
+putc D2
+getc D2
+putc D2
+getc D3
+mov UART_STATUS D4
+HLT
+";
+        let mut compiler = Compiler::new();
+        compiler.compile_str("test.mc", source).unwrap();
+        let program = compiler.program(Value::new(0xf000)).unwrap();
+        machine.load_program(&program).unwrap();
+        assert_eq!(machine.run(Some(1000)), RunOutcome::Halted);
+
+        assert_eq!(output.contents(), b"hi");
+        let cell = |name| {
+            let addr = program.symbols.get(name).expect("the register is allocated").value;
+            *machine.memory().read(addr).unwrap()
+        };
+        assert_eq!(cell("D1"), INPUT_READY | OUTPUT_READY);
+        // the input ended
+        assert_eq!((cell("D3"), cell("D4")), (0, OUTPUT_READY));
+    }
+}
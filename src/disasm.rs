@@ -0,0 +1,195 @@
+use std::fmt::Write;
+
+use crate::{
+    symbols::{SymbolKind, Symbols},
+    Command, Op, Value,
+};
+
+// undecodable bytes shown per data line
+const DATA_LEN: usize = 8;
+
+#[derive(Debug, Clone)]
+pub enum LineKind {
+    Command(Command),
+    /// Bytes that do not start a valid command.
+    Data,
+}
+
+/// One decoded command or a run of data, with the bytes it came from.
+#[derive(Debug, Clone)]
+pub struct Line {
+    pub addr: Value,
+    pub bytes: Vec<u8>,
+    pub kind: LineKind,
+}
+
+/// Decodes `bytes` loaded at `origin`.
+///
+/// Bytes that do not start a command are collected into data lines, and
+/// decoding resumes at the next byte that does.
+pub fn decode(origin: Value, bytes: &[u8]) -> Vec<Line> {
+    let mut lines: Vec<Line> = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let addr = Value::new(origin.wrapping_add(offset as u16));
+        let rest = &bytes[offset..];
+        let command = Command::decode(rest).ok();
+
+        if let Some(command) = command {
+            lines.push(Line {
+                addr,
+                bytes: rest[..Command::SIZE].to_vec(),
+                kind: LineKind::Command(command),
+            });
+            offset += Command::SIZE;
+            continue;
+        }
+
+        match lines.last_mut() {
+            Some(last) if matches!(last.kind, LineKind::Data) && last.bytes.len() < DATA_LEN => {
+                last.bytes.push(rest[0])
+            }
+            _ => lines.push(Line {
+                addr,
+                bytes: vec![rest[0]],
+                kind: LineKind::Data,
+            }),
+        }
+        offset += 1;
+    }
+    lines
+}
+
+fn operand(value: Value, kinds: &[SymbolKind], symbols: &Symbols) -> String {
+    match symbols.name_of(value, kinds) {
+        Some(name) => name.to_owned(),
+        None => value.to_string(),
+    }
+}
+
+/// Formats `command` as source code, operands that are addresses are replaced
+/// by names from `symbols`.
+pub fn format_command(command: &Command, symbols: &Symbols) -> String {
+    let addresses = [SymbolKind::Label, SymbolKind::Define];
+    let a = operand(command.a(), &addresses, symbols);
+    // the second operand of `SET` is an immediate, only a label is likely
+    let b = match command.op() {
+        Op::Set => operand(command.b(), &[SymbolKind::Label], symbols),
+        _ => operand(command.b(), &addresses, symbols),
+    };
+
+    match command.op().operands() {
+        0 => command.op().to_string(),
+        _ => format!("{} {a} {b}", command.op()),
+    }
+}
+
+/// Formats `lines` with their addresses, raw bytes and the labels pointing
+/// at them.
+pub fn listing(lines: &[Line], symbols: &Symbols) -> String {
+    let mut listing = String::new();
+    for line in lines {
+        for label in symbols.iter() {
+            if label.kind == SymbolKind::Label && label.value == line.addr {
+                writeln!(listing, "{}:", label.name).unwrap();
+            }
+        }
+
+        let bytes = line
+            .bytes
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let text = match &line.kind {
+            LineKind::Command(command) => format_command(command, symbols),
+            LineKind::Data => {
+                let data = line.bytes.iter().map(|byte| format!("0x{byte:02x}"));
+                format!(".bytes {}", data.collect::<Vec<_>>().join(" "))
+            }
+        };
+        writeln!(listing, "    {}  {bytes:<14}  {text}", line.addr).unwrap();
+    }
+    listing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(op: Op, a: u16, b: u16) -> Command {
+        Command::new(op, Value::new(a), Value::new(b))
+    }
+
+    fn encode(op: Op, a: u16, b: u16) -> [u8; Command::SIZE] {
+        let mut bytes = [0; Command::SIZE];
+        command(op, a, b).encode(&mut bytes);
+        bytes
+    }
+
+    fn symbols() -> Symbols {
+        let mut symbols = Symbols::new();
+        symbols.insert("X".into(), Value::new(0x10), SymbolKind::Define);
+        symbols.insert("Y".into(), Value::new(0x12), SymbolKind::Define);
+        symbols.insert("loop".into(), Value::new(0x100), SymbolKind::Label);
+        symbols.insert("N".into(), Value::new(0x100), SymbolKind::Define);
+        symbols
+    }
+
+    #[test]
+    fn bad_bytes_become_data_until_a_command_starts() {
+        // 11 bytes without a valid op, a command and a command cut short
+        let mut bytes = vec![0; 10];
+        bytes.push(9);
+        bytes.extend(encode(Op::Set, 0x10, 0x20));
+        bytes.extend([0xff, 0xff]);
+
+        let lines = decode(Value::new(0x100), &bytes);
+        let lines = lines
+            .iter()
+            .map(|line| {
+                let command = matches!(line.kind, LineKind::Command(_));
+                (*line.addr, line.bytes.len(), command)
+            })
+            .collect::<Vec<_>>();
+        let expected = [
+            (0x100, DATA_LEN, false),
+            (0x108, 3, false),
+            (0x10b, Command::SIZE, true),
+            (0x110, 2, false),
+        ];
+        assert_eq!(lines, expected);
+    }
+
+    #[test]
+    fn operands_are_named() {
+        let symbols = symbols();
+        let format = |op, a, b| format_command(&command(op, a, b), &symbols);
+        assert_eq!(format(Op::Sub, 0x10, 0x12), "SUB X Y");
+        assert_eq!(format(Op::Sub, 0x30, 0x10), "SUB 0x0030 X");
+        // labels are preferred over defines
+        assert_eq!(format(Op::Lod, 0x10, 0x100), "LOD X loop");
+        assert_eq!(format(Op::Hlt, 0, 0), "HLT");
+    }
+
+    #[test]
+    fn set_names_its_immediate_only_after_labels() {
+        let symbols = symbols();
+        let format = |a, b| format_command(&command(Op::Set, a, b), &symbols);
+        assert_eq!(format(0x10, 0x12), "SET X 0x0012");
+        assert_eq!(format(0x10, 0x100), "SET X loop");
+    }
+
+    #[test]
+    fn listing_shows_labels_bytes_and_data() {
+        let mut bytes = encode(Op::Hlt, 0, 0).to_vec();
+        bytes.push(0);
+        let listing = listing(&decode(Value::new(0x100), &bytes), &symbols());
+        let expected = "\
+loop:
+    0x0100  07 00 00 00 00  HLT
+    0x0105  00              .bytes 0x00
+";
+        assert_eq!(listing, expected);
+    }
+}
@@ -0,0 +1,384 @@
+use std::{path::Path, str::FromStr};
+
+use crate::Value;
+
+/// Bytes placed at a fixed address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub origin: Value,
+    pub bytes: Vec<u8>,
+}
+
+impl Segment {
+    /// One past the last address covered by the segment.
+    pub fn end(&self) -> usize {
+        *self.origin as usize + self.bytes.len()
+    }
+}
+
+/// A memory image made of [`Segment`]s, as produced by the compiler and
+/// loaded into a [`Machine`](crate::machine::Machine).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Image {
+    pub segments: Vec<Segment>,
+}
+
+impl Image {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates an image from a flat binary loaded at `origin`.
+    pub fn from_flat(origin: Value, bytes: Vec<u8>) -> Self {
+        Image {
+            segments: vec![Segment { origin, bytes }],
+        }
+    }
+
+    pub fn push(&mut self, origin: Value, bytes: Vec<u8>) {
+        self.segments.push(Segment { origin, bytes });
+    }
+
+    /// The lowest address covered by the image.
+    pub fn origin(&self) -> Option<Value> {
+        self.segments.iter().map(|segment| segment.origin).min_by_key(|v| **v)
+    }
+
+    /// One past the highest address covered by the image.
+    pub fn end(&self) -> usize {
+        self.segments.iter().map(Segment::end).max().unwrap_or(0)
+    }
+
+    /// Lays all segments out in one block starting at [`Image::origin`],
+    /// gaps between them are filled with zeros.
+    pub fn flatten(&self) -> (Value, Vec<u8>) {
+        let origin = self.origin().unwrap_or(Value::new(0));
+        let mut bytes = vec![0u8; self.end().saturating_sub(*origin as usize)];
+        for segment in &self.segments {
+            let start = *segment.origin as usize - *origin as usize;
+            bytes[start..start + segment.bytes.len()].copy_from_slice(&segment.bytes);
+        }
+        (origin, bytes)
+    }
+}
+
+/// File formats an [`Image`] can be stored in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// A flat binary without any addresses.
+    Bin,
+    /// Intel HEX.
+    Ihex,
+    /// Motorola S-record.
+    Srec,
+}
+
+impl Format {
+    /// Guesses the format from the extension of `path`.
+    pub fn from_path(path: &Path) -> Option<Format> {
+        match path.extension()?.to_str()? {
+            "bin" | "rom" => Some(Format::Bin),
+            "hex" | "ihex" | "ihx" => Some(Format::Ihex),
+            "srec" | "s19" | "mot" => Some(Format::Srec),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Bin => "bin",
+            Format::Ihex => "hex",
+            Format::Srec => "srec",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bin" => Ok(Format::Bin),
+            "hex" | "ihex" => Ok(Format::Ihex),
+            "srec" => Ok(Format::Srec),
+            _ => Err(format!("unknown image format `{s}`")),
+        }
+    }
+}
+
+/// An error in a text image, `line` starts at 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageError {
+    pub line: usize,
+    pub reason: String,
+}
+
+impl core::fmt::Display for ImageError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+// bytes of data per written record
+const RECORD_LEN: usize = 16;
+
+fn parse_hex_bytes(digits: &str) -> Result<Vec<u8>, String> {
+    if digits.len() % 2 != 0 || !digits.is_ascii() {
+        return Err("odd number of hex digits".to_owned());
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16))
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("invalid hex digits: {e}"))
+}
+
+fn hex_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02X}")).collect()
+}
+
+impl Image {
+    // appends `bytes` to the last segment if they follow it directly
+    fn append(&mut self, addr: u32, bytes: &[u8]) -> Result<(), String> {
+        if addr as usize + bytes.len() > 0x10000 {
+            return Err(format!("data at {addr:#x} does not fit in 16-bit addresses"));
+        }
+        match self.segments.last_mut() {
+            Some(last) if last.end() == addr as usize => last.bytes.extend_from_slice(bytes),
+            _ => self.push(Value::new(addr as u16), bytes.to_vec()),
+        }
+        Ok(())
+    }
+
+    // every run of at most `RECORD_LEN` bytes, with its address
+    fn records(&self) -> impl Iterator<Item = (u16, &[u8])> {
+        self.segments.iter().flat_map(|segment| {
+            segment
+                .bytes
+                .chunks(RECORD_LEN)
+                .enumerate()
+                .map(move |(idx, chunk)| {
+                    let addr = segment.origin.wrapping_add((idx * RECORD_LEN) as u16);
+                    (addr, chunk)
+                })
+        })
+    }
+
+    /// Writes the image as Intel HEX, with a start address record for
+    /// `entry`.
+    pub fn write_ihex(&self, entry: Option<Value>) -> String {
+        fn record(kind: u8, addr: u16, data: &[u8]) -> String {
+            let mut bytes = vec![data.len() as u8];
+            bytes.extend_from_slice(&addr.to_be_bytes());
+            bytes.push(kind);
+            bytes.extend_from_slice(data);
+            let sum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+            bytes.push(sum.wrapping_neg());
+            format!(":{}\n", hex_string(&bytes))
+        }
+
+        let mut text = String::new();
+        for (addr, data) in self.records() {
+            text += &record(0x00, addr, data);
+        }
+        if let Some(entry) = entry {
+            text += &record(0x05, 0, &(*entry as u32).to_be_bytes());
+        }
+        text += &record(0x01, 0, &[]);
+        text
+    }
+
+    /// Reads an Intel HEX image and its start address, if any.
+    pub fn read_ihex(text: &str) -> Result<(Image, Option<Value>), ImageError> {
+        let mut image = Image::new();
+        let mut entry = None;
+        // upper bits from extended address records
+        let mut base = 0u32;
+
+        for (idx, line) in text.lines().enumerate() {
+            let error = |reason: String| ImageError {
+                line: idx + 1,
+                reason,
+            };
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let Some(digits) = line.strip_prefix(':') else {
+                return Err(error("record does not start with `:`".to_owned()));
+            };
+            let bytes = parse_hex_bytes(digits).map_err(error)?;
+            if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+                return Err(error("record length does not match".to_owned()));
+            }
+            if bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+                return Err(error("checksum mismatch".to_owned()));
+            }
+
+            let addr = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+            let data = &bytes[4..bytes.len() - 1];
+            match bytes[3] {
+                0x00 => image.append(base + addr, data).map_err(error)?,
+                0x01 => break,
+                0x02 | 0x04 if data.len() == 2 => {
+                    let upper = u16::from_be_bytes([data[0], data[1]]) as u32;
+                    // segment addresses are shifted by 4, linear ones by 16
+                    base = if bytes[3] == 0x02 { upper << 4 } else { upper << 16 };
+                }
+                0x03 | 0x05 if data.len() == 4 => {
+                    let start = u32::from_be_bytes(data.try_into().unwrap());
+                    // segment records hold CS:IP
+                    let start = if bytes[3] == 0x03 {
+                        ((start >> 16) << 4) + (start & 0xffff)
+                    } else {
+                        start
+                    };
+                    let start = u16::try_from(start)
+                        .map_err(|_| error(format!("start address {start:#x} out of range")))?;
+                    entry = Some(Value::new(start));
+                }
+                kind => return Err(error(format!("unsupported record type {kind:02X}"))),
+            }
+        }
+
+        Ok((image, entry))
+    }
+
+    /// Writes the image as S-records, with an `S9` record for `entry`.
+    pub fn write_srec(&self, entry: Option<Value>) -> String {
+        fn record(kind: char, addr: u16, data: &[u8]) -> String {
+            let mut bytes = vec![(data.len() + 3) as u8];
+            bytes.extend_from_slice(&addr.to_be_bytes());
+            bytes.extend_from_slice(data);
+            let sum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+            bytes.push(!sum);
+            format!("S{kind}{}\n", hex_string(&bytes))
+        }
+
+        let mut text = record('0', 0, b"mini-cpu");
+        let mut count = 0usize;
+        for (addr, data) in self.records() {
+            text += &record('1', addr, data);
+            count += 1;
+        }
+        if let Ok(count) = u16::try_from(count) {
+            text += &record('5', count, &[]);
+        }
+        text += &record('9', entry.map_or(0, |entry| *entry), &[]);
+        text
+    }
+
+    /// Reads an S-record image and its start address, if any.
+    pub fn read_srec(text: &str) -> Result<(Image, Option<Value>), ImageError> {
+        let mut image = Image::new();
+        let mut entry = None;
+
+        for (idx, line) in text.lines().enumerate() {
+            let error = |reason: String| ImageError {
+                line: idx + 1,
+                reason,
+            };
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut chars = line.chars();
+            let (Some('S'), Some(kind)) = (chars.next(), chars.next()) else {
+                return Err(error("record does not start with `S`".to_owned()));
+            };
+            let bytes = parse_hex_bytes(chars.as_str()).map_err(error)?;
+            if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+                return Err(error("record length does not match".to_owned()));
+            }
+            if bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0xff {
+                return Err(error("checksum mismatch".to_owned()));
+            }
+
+            let addr_len = match kind {
+                '0' | '1' | '5' | '9' => 2,
+                '2' | '6' | '8' => 3,
+                '3' | '7' => 4,
+                _ => return Err(error(format!("unsupported record type S{kind}"))),
+            };
+            if bytes.len() < addr_len + 2 {
+                return Err(error("record too short".to_owned()));
+            }
+            let addr = bytes[1..1 + addr_len]
+                .iter()
+                .fold(0u32, |addr, byte| addr << 8 | *byte as u32);
+            let data = &bytes[1 + addr_len..bytes.len() - 1];
+            match kind {
+                '1' | '2' | '3' => image.append(addr, data).map_err(error)?,
+                '7' | '8' | '9' => {
+                    let start = u16::try_from(addr)
+                        .map_err(|_| error(format!("start address {addr:#x} out of range")))?;
+                    entry = Some(Value::new(start));
+                }
+                // header and record counts
+                _ => {}
+            }
+        }
+
+        Ok((image, entry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a segment spanning several records and one on its own
+    fn image() -> Image {
+        let mut image = Image::new();
+        image.push(Value::new(0xf000), (0..40).collect());
+        image.push(Value::new(0xff00), vec![0xaa, 0x55]);
+        image
+    }
+
+    fn error(line: usize, reason: &str) -> ImageError {
+        ImageError {
+            line,
+            reason: reason.to_owned(),
+        }
+    }
+
+    #[test]
+    fn ihex_round_trip() {
+        let entry = Some(Value::new(0xf005));
+        let text = image().write_ihex(entry);
+        assert_eq!(Image::read_ihex(&text), Ok((image(), entry)));
+    }
+
+    #[test]
+    fn ihex_rejects_bad_records() {
+        assert!(Image::read_ihex(":0100000001FE\n:00000001FF\n").is_ok());
+        assert_eq!(
+            Image::read_ihex(":0100000001FE\n:0100000001FF\n"),
+            Err(error(2, "checksum mismatch"))
+        );
+        assert_eq!(
+            Image::read_ihex(":0200000001FD\n"),
+            Err(error(1, "record length does not match"))
+        );
+    }
+
+    #[test]
+    fn srec_round_trip() {
+        let entry = Some(Value::new(0xf005));
+        let text = image().write_srec(entry);
+        assert_eq!(Image::read_srec(&text), Ok((image(), entry)));
+    }
+
+    #[test]
+    fn srec_rejects_bad_records() {
+        assert!(Image::read_srec("S104000001FA\n").is_ok());
+        assert_eq!(
+            Image::read_srec("S104000001FA\nS104000001FB\n"),
+            Err(error(2, "checksum mismatch"))
+        );
+        assert_eq!(
+            Image::read_srec("S105000001F9\n"),
+            Err(error(1, "record length does not match"))
+        );
+    }
+}
@@ -0,0 +1,684 @@
+#![feature(str_as_str)]
+//! A tiny 16-bit CPU together with an assembler for its `.mc` source files.
+//!
+//! Programs are compiled with [`compiler::Compiler`] into a [`compiler::Program`],
+//! which is loaded into a [`machine::Machine`] and executed there.
+//!
+//! ```
+//! use mini_cpu::{compiler::Compiler, machine::Machine, RunOutcome, Value};
+//!
+//! let mut compiler = Compiler::new();
+//! compiler.compile_str("main.mc", "SET 0x02 12\nHLT\n").unwrap();
+//! let program = compiler.program(Value::new(0xf000)).unwrap();
+//!
+//! let mut machine = Machine::new(0x10000);
+//! machine.load_program(&program).unwrap();
+//! assert_eq!(machine.run(None), RunOutcome::Halted);
+//! assert_eq!(*machine.memory().read(Value::new(0x02)).unwrap(), 12);
+//! ```
+pub mod bus;
+pub mod compiler;
+pub mod debug_info;
+pub mod debugger;
+pub mod devices;
+pub mod disasm;
+pub mod image;
+pub mod machine;
+pub mod macros;
+pub mod parser;
+pub mod protection;
+pub mod snapshot;
+pub mod symbols;
+pub mod trace;
+
+use std::{
+    num::ParseIntError,
+    ops::{Deref, DerefMut},
+    str::FromStr,
+};
+
+use bus::Bus;
+use protection::Access;
+
+/// The operation of a [`Command`], stored in its first byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Neq,
+    Sub,
+    Set,
+    Shr,
+    Lod,
+    Str,
+    Hlt,
+}
+
+impl Op {
+    /// Number of operands the op takes in source code.
+    ///
+    /// Encoded commands always carry both operands, unused ones are zero.
+    pub fn operands(&self) -> usize {
+        match self {
+            Op::Hlt => 0,
+            _ => 2,
+        }
+    }
+
+    pub fn execute(&self, mem: &mut dyn Bus, a: Value, b: Value) -> Result<(), Fault> {
+        match self {
+            // *a !== *b
+            Op::Neq => {
+                let a = mem.read(a)?;
+                let b = mem.read(b)?;
+                let neq = a != b;
+                mem.write(a, (neq as u16).into())?;
+            }
+            // *a -= *b
+            Op::Sub => {
+                let tmp = mem.read(a)?;
+                let (result, _) = tmp.0.overflowing_sub(mem.read(b)?.0);
+                mem.write(a, result.into())?;
+            }
+            // *a = b
+            Op::Set => {
+                mem.write(a, b)?;
+            }
+
+            // *a >>= *b
+            Op::Shr => {
+                let tmp = mem.read(a)?;
+                let (result, _) = tmp.0.overflowing_shr(mem.read(b)?.0 as u32);
+                mem.write(a, result.into())?;
+            }
+            // *a = **b
+            Op::Lod => {
+                let ptr = mem.read(b)?;
+                let data = mem.read(ptr)?;
+                mem.write(a, data)?;
+            }
+            // **b = *a
+            Op::Str => {
+                let data = mem.read(a)?;
+                let ptr = mem.read(b)?;
+
+                mem.write(ptr, data)?;
+            }
+            // stop the machine
+            Op::Hlt => {}
+        }
+        // *c = a
+        // b = *c
+        Ok(())
+    }
+}
+
+/// Returned when a byte or mnemonic does not name an [`Op`].
+#[derive(Debug)]
+pub struct InvalidOp;
+
+impl FromStr for Op {
+    type Err = InvalidOp;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "NEQ" => Ok(Op::Neq),
+            "SUB" => Ok(Op::Sub),
+            "SET" => Ok(Op::Set),
+            "SHR" => Ok(Op::Shr),
+            "LOD" => Ok(Op::Lod),
+            "STR" => Ok(Op::Str),
+            "HLT" => Ok(Op::Hlt),
+            _ => Err(InvalidOp),
+        }
+    }
+}
+
+impl core::fmt::Display for Op {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mnemonic = match self {
+            Op::Neq => "NEQ",
+            Op::Sub => "SUB",
+            Op::Set => "SET",
+            Op::Shr => "SHR",
+            Op::Lod => "LOD",
+            Op::Str => "STR",
+            Op::Hlt => "HLT",
+        };
+        f.write_str(mnemonic)
+    }
+}
+
+impl TryFrom<u8> for Op {
+    type Error = InvalidOp;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Op::Neq),
+            2 => Ok(Op::Sub),
+            3 => Ok(Op::Set),
+            4 => Ok(Op::Shr),
+            5 => Ok(Op::Lod),
+            6 => Ok(Op::Str),
+            7 => Ok(Op::Hlt),
+            _ => Err(InvalidOp),
+        }
+    }
+}
+
+impl From<Op> for u8 {
+    fn from(value: Op) -> Self {
+        match value {
+            Op::Neq => 1,
+            Op::Sub => 2,
+            Op::Set => 3,
+            Op::Shr => 4,
+            Op::Lod => 5,
+            Op::Str => 6,
+            Op::Hlt => 7,
+        }
+    }
+}
+
+/// A single instruction: an [`Op`] with its two operands.
+#[derive(Debug, Clone, Copy)]
+pub struct Command {
+    op: Op,
+    a: Value,
+    b: Value,
+}
+
+impl Command {
+    /// Size of an encoded command in bytes.
+    pub const SIZE: usize = 5;
+
+    pub fn new(op: Op, a: Value, b: Value) -> Command {
+        Command { op, a, b }
+    }
+
+    pub fn op(&self) -> Op {
+        self.op
+    }
+
+    pub fn a(&self) -> Value {
+        self.a
+    }
+
+    pub fn b(&self) -> Value {
+        self.b
+    }
+
+    pub fn execute(&self, mem: &mut dyn Bus) -> Result<(), Fault> {
+        self.op.execute(mem, self.a, self.b)
+    }
+
+    /// Writes the command into the first [`Command::SIZE`] bytes of `memory`.
+    pub fn encode(&self, memory: &mut [u8]) {
+        assert!(memory.len() >= 5);
+        // 1 + 2 + 2 = 5bytes
+        memory[0] = self.op.into();
+        memory[1..3].copy_from_slice(&self.a.to_le_bytes());
+        memory[3..5].copy_from_slice(&self.b.to_le_bytes());
+    }
+
+    /// Reads a command from the first [`Command::SIZE`] bytes of `memory`.
+    pub fn decode(memory: &[u8]) -> Result<Command, FaultKind> {
+        if memory.len() < Command::SIZE {
+            return Err(FaultKind::DecodePastEnd);
+        }
+        let operator = Op::try_from(memory[0]).map_err(|_| FaultKind::InvalidOp)?;
+        let a = Value(u16::from_le_bytes(memory[1..3].try_into().unwrap()));
+        let b = Value(u16::from_le_bytes(memory[3..5].try_into().unwrap()));
+        Ok(Command::new(operator, a, b))
+    }
+}
+
+impl core::fmt::Display for Command {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{} {} {}", self.op, self.a, self.b)
+    }
+}
+
+/// The byte addressed memory of the machine.
+///
+/// All operands are pointers to little endian 16-bit cells in it. Commands
+/// access it as a [`Bus`](bus::Bus), the inherent methods only touch the RAM.
+#[derive(Debug)]
+pub struct Memory {
+    memory: Vec<u8>,
+    policy: EdgePolicy,
+    devices: Vec<bus::Mapping>,
+    regions: Vec<protection::Region>,
+    // cells written since `Memory::start_journal`, if it was called
+    journal: Option<Vec<CellWrite>>,
+    // device writes made while the journal runs, they can not be taken back
+    device_journal: Vec<bus::DeviceWrite>,
+}
+
+/// What happens to accesses reaching past the end of the memory, and to the
+/// program counter moving past `0xffff`.
+///
+/// Different hardware revisions behave differently here.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EdgePolicy {
+    /// The access faults and the program counter overflows.
+    #[default]
+    Trap,
+    /// Addresses continue at the start of the memory, and the program
+    /// counter at `0x0000`.
+    Wrap,
+    /// Missing bytes read as zero and writes to them are dropped. The program
+    /// counter has no bytes to extend, it wraps like [`EdgePolicy::Wrap`].
+    ZeroExtend,
+}
+
+impl FromStr for EdgePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "trap" => Ok(EdgePolicy::Trap),
+            "wrap" => Ok(EdgePolicy::Wrap),
+            "zero" => Ok(EdgePolicy::ZeroExtend),
+            _ => Err(format!("unknown edge policy `{s}`")),
+        }
+    }
+}
+
+/// A write of a 16-bit cell, recorded while the journal of a [`Memory`] is
+/// running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CellWrite {
+    pub addr: Value,
+    pub old: Value,
+    pub new: Value,
+}
+
+impl Memory {
+    /// Creates a zeroed memory of `size` bytes.
+    pub fn new(size: usize) -> Memory {
+        Memory {
+            memory: vec![0; size],
+            policy: EdgePolicy::default(),
+            devices: Vec::new(),
+            regions: Vec::new(),
+            journal: None,
+            device_journal: Vec::new(),
+        }
+    }
+
+    pub fn policy(&self) -> EdgePolicy {
+        self.policy
+    }
+
+    /// Selects what accesses past the end of the memory do.
+    pub fn set_policy(&mut self, policy: EdgePolicy) {
+        self.policy = policy;
+    }
+
+    // the index of the byte at `addr`, `None` if it is past the end and the
+    // policy does not wrap
+    fn index(&self, addr: usize) -> Option<usize> {
+        match self.policy {
+            _ if addr < self.memory.len() => Some(addr),
+            EdgePolicy::Wrap => addr.checked_rem(self.memory.len()),
+            EdgePolicy::Trap | EdgePolicy::ZeroExtend => None,
+        }
+    }
+
+    // the bytes starting at `addr`, `None` if the policy traps on one of them
+    fn bytes<const N: usize>(&self, addr: Value) -> Option<[u8; N]> {
+        let mut bytes = [0; N];
+        for (offset, byte) in bytes.iter_mut().enumerate() {
+            match self.index(addr.0 as usize + offset) {
+                Some(index) => *byte = self.memory[index],
+                None if self.policy == EdgePolicy::ZeroExtend => {}
+                None => return None,
+            }
+        }
+        Some(bytes)
+    }
+
+    /// Copies `bytes` into the memory starting at `addr`.
+    pub fn load(&mut self, addr: Value, bytes: &[u8]) -> Result<(), Fault> {
+        let start = addr.0 as usize;
+        let memory = self
+            .memory
+            .get_mut(start..start + bytes.len())
+            .ok_or(Fault::new(FaultKind::WriteOutOfBounds, addr))?;
+        memory.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Decodes the command stored at `addr`.
+    pub fn fetch(&self, addr: Value) -> Result<Command, Fault> {
+        self.check(addr, Command::SIZE as u32, Access::Execute)?;
+        let bytes = self
+            .bytes::<{ Command::SIZE }>(addr)
+            .ok_or(Fault::new(FaultKind::DecodePastEnd, addr))?;
+        Command::decode(&bytes).map_err(|kind| Fault::new(kind, addr))
+    }
+
+    /// Reads the 16-bit cell at `ptr`.
+    pub fn read(&self, ptr: Value) -> Result<Value, Fault> {
+        self.check(ptr, 2, Access::Read)?;
+        let bytes = self
+            .bytes(ptr)
+            .ok_or(Fault::new(FaultKind::ReadOutOfBounds, ptr))?;
+        Ok(Value(u16::from_le_bytes(bytes)))
+    }
+
+    /// Reads the 16-bit cell at `ptr` if it lies within the memory, even if
+    /// it is protected.
+    pub fn peek(&self, ptr: Value) -> Option<Value> {
+        self.bytes(ptr).map(|bytes| Value(u16::from_le_bytes(bytes)))
+    }
+
+    /// Writes `value` into the 16-bit cell at `ptr`.
+    pub fn write(&mut self, ptr: Value, value: Value) -> Result<(), Fault> {
+        self.check(ptr, 2, Access::Write)?;
+        self.poke(ptr, value)
+    }
+
+    // writes like `write` even if the cell is protected, for the cells the
+    // CPU itself keeps its state in
+    pub(crate) fn poke(&mut self, ptr: Value, value: Value) -> Result<(), Fault> {
+        // fails if any byte traps, before anything is written
+        let Some(old) = self.bytes(ptr) else {
+            return Err(Fault::new(FaultKind::WriteOutOfBounds, ptr));
+        };
+        self.store(ptr, value);
+        let old = Value(u16::from_le_bytes(old));
+        if let Some(journal) = &mut self.journal {
+            journal.push(CellWrite {
+                addr: ptr,
+                old,
+                new: value,
+            });
+        }
+        Ok(())
+    }
+
+    // writes the bytes of the cell at `ptr` that lie within the memory
+    fn store(&mut self, ptr: Value, value: Value) {
+        let indices = [self.index(ptr.0 as usize), self.index(ptr.0 as usize + 1)];
+        for (index, byte) in indices.into_iter().zip(value.0.to_le_bytes()) {
+            // missing bytes are dropped when zero extending
+            if let Some(index) = index {
+                self.memory[index] = byte;
+            }
+        }
+    }
+
+    /// Puts back the value a recorded write replaced, even if the cell is
+    /// protected. The undo is not recorded.
+    pub fn undo(&mut self, write: &CellWrite) {
+        self.store(write.addr, write.old);
+    }
+
+    /// Starts recording every [`Memory::write`] and every write handled by a
+    /// device, dropping earlier records.
+    pub fn start_journal(&mut self) {
+        self.journal = Some(Vec::new());
+        self.device_journal.clear();
+    }
+
+    /// Stops recording writes and returns the ones made since
+    /// [`Memory::start_journal`], device writes are dropped.
+    pub fn take_journal(&mut self) -> Vec<CellWrite> {
+        self.device_journal.clear();
+        self.journal.take().unwrap_or_default()
+    }
+
+    /// The writes recorded so far, empty if the journal is not running.
+    pub fn journal(&self) -> &[CellWrite] {
+        self.journal.as_deref().unwrap_or_default()
+    }
+
+    /// The writes devices handled so far, empty if the journal is not
+    /// running.
+    pub fn device_journal(&self) -> &[bus::DeviceWrite] {
+        &self.device_journal
+    }
+}
+
+/// What went wrong in a [`Fault`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    /// A cell was read past the end of the memory.
+    ReadOutOfBounds,
+    /// A cell was written past the end of the memory.
+    WriteOutOfBounds,
+    /// A command was fetched from less than [`Command::SIZE`] bytes.
+    DecodePastEnd,
+    /// A command does not start with a valid [`Op`].
+    InvalidOp,
+    /// A cell was read in an execute-only or no-access region.
+    ReadProtected,
+    /// A cell was written in a protected region.
+    WriteProtected,
+    /// A command was fetched from a no-access region.
+    ExecuteProtected,
+}
+
+/// A memory access the machine can not perform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    pub kind: FaultKind,
+    /// The address that was accessed.
+    pub address: Value,
+    /// The command being executed, unknown outside of a machine.
+    pub pc: Option<Value>,
+}
+
+impl Fault {
+    pub fn new(kind: FaultKind, address: Value) -> Fault {
+        Fault {
+            kind,
+            address,
+            pc: None,
+        }
+    }
+
+    /// Attributes the fault to the command at `pc`.
+    pub fn at(self, pc: Value) -> Fault {
+        Fault {
+            pc: Some(pc),
+            ..self
+        }
+    }
+}
+
+impl core::fmt::Display for Fault {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let address = self.address;
+        match self.kind {
+            FaultKind::ReadOutOfBounds => write!(f, "read out of bounds at {address}")?,
+            FaultKind::WriteOutOfBounds => write!(f, "write out of bounds at {address}")?,
+            FaultKind::DecodePastEnd => write!(f, "command at {address} runs past the memory")?,
+            FaultKind::InvalidOp => write!(f, "invalid op at {address}")?,
+            FaultKind::ReadProtected => write!(f, "read of protected memory at {address}")?,
+            FaultKind::WriteProtected => write!(f, "write to protected memory at {address}")?,
+            FaultKind::ExecuteProtected => write!(f, "execution of protected memory at {address}")?,
+        }
+        match self.pc {
+            Some(pc) => write!(f, " (pc {pc})"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl From<Vec<u8>> for Memory {
+    fn from(memory: Vec<u8>) -> Self {
+        Memory {
+            memory,
+            policy: EdgePolicy::default(),
+            devices: Vec::new(),
+            regions: Vec::new(),
+            journal: None,
+            device_journal: Vec::new(),
+        }
+    }
+}
+
+impl Deref for Memory {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.memory
+    }
+}
+
+impl DerefMut for Memory {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.memory
+    }
+}
+
+/// A 16-bit machine word, used both for addresses and data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Value(u16);
+
+impl core::fmt::Display for Value {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "0x{:04x}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Aborted;
+
+/// Why a program stopped running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunOutcome {
+    /// A `HLT` command was executed.
+    Halted,
+    /// A command could not be fetched or accessed memory it can not.
+    Fault(Fault),
+    /// No command can follow the one at `pc` without the program counter
+    /// overflowing.
+    PcOverflow { pc: Value },
+    /// A macro called before the command at `pc` failed, the machine keeps
+    /// its error.
+    MacroFailed { pc: Value },
+    /// `steps` commands were executed without the program stopping.
+    StepLimit { steps: usize },
+}
+
+impl RunOutcome {
+    /// The command the program stopped at, if it stopped on an error.
+    pub fn pc(&self) -> Option<Value> {
+        match self {
+            RunOutcome::Fault(fault) => fault.pc,
+            RunOutcome::PcOverflow { pc } | RunOutcome::MacroFailed { pc } => Some(*pc),
+            RunOutcome::Halted | RunOutcome::StepLimit { .. } => None,
+        }
+    }
+}
+
+impl core::fmt::Display for RunOutcome {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            RunOutcome::Halted => write!(f, "halted"),
+            RunOutcome::Fault(fault) => write!(f, "{fault}"),
+            RunOutcome::PcOverflow { pc } => write!(f, "program counter overflow after {pc}"),
+            RunOutcome::MacroFailed { pc } => write!(f, "macro failed before the command at {pc}"),
+            RunOutcome::StepLimit { steps } => write!(f, "step limit reached after {steps} steps"),
+        }
+    }
+}
+
+impl Value {
+    pub const fn new(value: u16) -> Value {
+        Value(value)
+    }
+
+    /// The address following a command at this one, `policy` decides if
+    /// it may wrap around.
+    pub(crate) fn next_command(&self, policy: EdgePolicy) -> Result<Value, Aborted> {
+        match policy {
+            EdgePolicy::Trap => self.0.checked_add(5).map(Value).ok_or(Aborted),
+            EdgePolicy::Wrap | EdgePolicy::ZeroExtend => Ok(Value(self.0.wrapping_add(5))),
+        }
+    }
+}
+
+impl Deref for Value {
+    type Target = u16;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for Value {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl From<u16> for Value {
+    fn from(value: u16) -> Self {
+        Value(value)
+    }
+}
+
+impl FromStr for Value {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(str) = s.strip_prefix("0x") {
+            u16::from_str_radix(str, 16).map(Value)
+        } else if let Some(str) = s.strip_prefix("0b") {
+            u16::from_str_radix(str, 2).map(Value)
+        } else if let Some(str) = s.strip_prefix("0o") {
+            u16::from_str_radix(str, 8).map(Value)
+        } else {
+            u16::from_str(s).map(Value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a full memory with `policy` and the bytes 0xaa, 0xbb at its edges
+    fn memory(policy: EdgePolicy) -> Memory {
+        let mut memory = Memory::new(0x10000);
+        memory.set_policy(policy);
+        memory[0] = 0xaa;
+        memory[0xffff] = 0xbb;
+        memory
+    }
+
+    #[test]
+    fn trap_faults_at_the_edge() {
+        let mut memory = memory(EdgePolicy::Trap);
+        let edge = Value(0xffff);
+        assert_eq!(memory.read(edge), Err(Fault::new(FaultKind::ReadOutOfBounds, edge)));
+        let written = memory.write(edge, Value(0x1234));
+        assert_eq!(written, Err(Fault::new(FaultKind::WriteOutOfBounds, edge)));
+        assert_eq!((memory[0], memory[0xffff]), (0xaa, 0xbb));
+        let fetched = memory.fetch(Value(0xfffc)).map_err(|fault| fault.kind);
+        assert_eq!(fetched.unwrap_err(), FaultKind::DecodePastEnd);
+        assert!(Value(0xfffb).next_command(EdgePolicy::Trap).is_err());
+    }
+
+    #[test]
+    fn wrap_continues_at_the_start() {
+        let mut memory = memory(EdgePolicy::Wrap);
+        assert_eq!(memory.read(Value(0xffff)), Ok(Value(0xaabb)));
+        memory.write(Value(0xffff), Value(0x1234)).unwrap();
+        assert_eq!((memory[0], memory[0xffff]), (0x12, 0x34));
+        assert_eq!(Value(0xfffb).next_command(EdgePolicy::Wrap).ok(), Some(Value(0)));
+    }
+
+    #[test]
+    fn zero_extend_reads_zeros_and_drops_writes() {
+        let mut memory = memory(EdgePolicy::ZeroExtend);
+        assert_eq!(memory.read(Value(0xffff)), Ok(Value(0x00bb)));
+        memory.write(Value(0xffff), Value(0x1234)).unwrap();
+        assert_eq!((memory[0], memory[0xffff]), (0xaa, 0x34));
+        let next = Value(0xfffb).next_command(EdgePolicy::ZeroExtend);
+        assert_eq!(next.ok(), Some(Value(0)));
+    }
+}
@@ -0,0 +1,724 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    ops::ControlFlow,
+};
+
+use crate::{
+    compiler::Program,
+    debug_info::DebugInfo,
+    image::Image,
+    macros::MacroCall,
+    snapshot::Snapshot,
+    trace::{Trace, TraceRecord},
+    CellWrite, Command, EdgePolicy, Fault, FaultKind, Memory, Op, RunOutcome, Value,
+};
+
+/// The cell holding the address of the next command to execute.
+///
+/// It is advanced before a command executes, so a command writing to it
+/// performs a jump. Protecting it only stops commands from accessing it, the
+/// machine still advances it.
+///
+/// The last command before it would overflow runs without advancing it, and
+/// has to halt or jump.
+pub const PC: Value = Value::new(0x00);
+
+/// The cells the interrupt mechanism works with.
+///
+/// An interrupt raised by a device is taken before the next command while
+/// the `enable` cell is not zero: the program counter is saved to
+/// `saved_pc`, `enable` is cleared and execution continues at the address in
+/// `vector`. A handler returns by setting `enable` and then jumping back
+/// through `saved_pc`, no interrupt is taken right after `enable` was set, so
+/// the two commands can not be interrupted.
+///
+/// Taking an interrupt uses the cells even if they are protected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interrupts {
+    pub vector: Value,
+    pub saved_pc: Value,
+    pub enable: Value,
+}
+
+impl Default for Interrupts {
+    fn default() -> Self {
+        Interrupts {
+            vector: Value::new(0x0a),
+            saved_pc: Value::new(0x0c),
+            enable: Value::new(0x0e),
+        }
+    }
+}
+
+// what is needed to take back one step
+#[derive(Debug)]
+struct Undo {
+    cycles: usize,
+    pending: bool,
+    shadow: bool,
+    // the memory writes of the step, in the order they were made
+    writes: Vec<CellWrite>,
+}
+
+/// A CPU together with its memory, independent of any [`Compiler`].
+///
+/// [`Compiler`]: crate::compiler::Compiler
+#[derive(Debug)]
+pub struct Machine {
+    memory: Memory,
+    entry: Value,
+    // loaded bytes, kept to restore the memory on reset
+    image: Image,
+    macro_calls: HashMap<Value, Vec<MacroCall>>,
+    cycles: usize,
+    trace: Option<Box<dyn Trace>>,
+    // why tracing stopped early
+    trace_error: Option<std::io::Error>,
+    // why a macro call stopped the machine
+    macro_error: Option<terl::Error>,
+    debug_info: DebugInfo,
+    interrupts: Interrupts,
+    // raised by a device and not yet taken
+    pending: bool,
+    // interrupts were just enabled, the next command runs first
+    shadow: bool,
+    // the latest steps that can be taken back, with the most kept
+    history: Option<(VecDeque<Undo>, usize)>,
+}
+
+impl Machine {
+    /// Creates a machine with `memory_size` bytes of zeroed memory.
+    ///
+    /// # Panics
+    ///
+    /// If the memory can not hold the [`PC`] cell.
+    pub fn new(memory_size: usize) -> Machine {
+        assert!(memory_size >= 2, "the memory must hold the program counter");
+        Machine {
+            memory: Memory::new(memory_size),
+            entry: Value::new(0),
+            image: Image::new(),
+            macro_calls: HashMap::new(),
+            cycles: 0,
+            trace: None,
+            trace_error: None,
+            macro_error: None,
+            debug_info: DebugInfo::new(),
+            interrupts: Interrupts::default(),
+            pending: false,
+            shadow: false,
+            history: None,
+        }
+    }
+
+    /// Copies `bytes` into the memory at `addr`, they are loaded again on
+    /// every [`Machine::reset`].
+    pub fn load(&mut self, addr: Value, bytes: &[u8]) -> Result<(), Fault> {
+        self.memory.load(addr, bytes)?;
+        self.image.push(addr, bytes.to_vec());
+        Ok(())
+    }
+
+    /// Loads every segment of `image`.
+    pub fn load_image(&mut self, image: &Image) -> Result<(), Fault> {
+        for segment in &image.segments {
+            self.load(segment.origin, &segment.bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Loads a compiled program and points the program counter at it.
+    pub fn load_program(&mut self, program: &Program) -> Result<(), Fault> {
+        self.load_image(&program.image)?;
+        for (pc, call) in &program.macro_calls {
+            self.macro_calls.entry(*pc).or_default().push(call.clone());
+        }
+        self.debug_info = program.debug_info.clone();
+        self.set_entry(program.entry);
+        Ok(())
+    }
+
+    /// Sets the address execution starts from, now and after a reset.
+    pub fn set_entry(&mut self, entry: Value) {
+        self.entry = entry;
+        self.set_pc(entry);
+    }
+
+    /// Restores the memory to the loaded image and restarts from the entry.
+    pub fn reset(&mut self) {
+        self.memory.fill(0);
+        for segment in &self.image.segments {
+            self.memory
+                .load(segment.origin, &segment.bytes)
+                .expect("the segment was loaded before");
+        }
+        self.memory.reset_devices();
+        self.set_pc(self.entry);
+        self.cycles = 0;
+        self.pending = false;
+        self.shadow = false;
+        self.clear_history();
+    }
+
+    /// Takes the state of the memory, the cycle counter, pending interrupts
+    /// and the devices.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            memory: self.memory.to_vec(),
+            cycles: self.cycles as u64,
+            pending: self.pending,
+            shadow: self.shadow,
+            devices: self.memory.save_devices(),
+        }
+    }
+
+    /// Continues from `snapshot`, which must have been taken from a machine
+    /// with the same memory size and devices.
+    ///
+    /// Protection is ignored like when loading. The loaded program stays, so
+    /// a reset still restarts it.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), String> {
+        if snapshot.memory.len() != self.memory.len() {
+            return Err(format!(
+                "the snapshot holds {} bytes of memory, but the machine has {}",
+                snapshot.memory.len(),
+                self.memory.len()
+            ));
+        }
+        self.memory.restore_devices(&snapshot.devices)?;
+        self.memory.copy_from_slice(&snapshot.memory);
+        self.cycles = snapshot.cycles as usize;
+        self.pending = snapshot.pending;
+        self.shadow = snapshot.shadow;
+        self.clear_history();
+        Ok(())
+    }
+
+    pub fn pc(&self) -> Value {
+        self.memory.peek(PC).expect("the memory holds the program counter")
+    }
+
+    fn set_pc(&mut self, pc: Value) {
+        self.memory.poke(PC, pc).expect("the memory holds the program counter")
+    }
+
+    /// Number of commands executed since the last reset.
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    /// Selects how accesses past the end of the memory and the program
+    /// counter passing `0xffff` behave.
+    pub fn set_policy(&mut self, policy: EdgePolicy) {
+        self.memory.set_policy(policy);
+    }
+
+    pub fn set_interrupts(&mut self, interrupts: Interrupts) {
+        self.interrupts = interrupts;
+    }
+
+    /// Raises an interrupt, taken once the program enables interrupts.
+    pub fn raise_interrupt(&mut self) {
+        self.pending = true;
+    }
+
+    // jumps to the interrupt handler if an interrupt can be taken
+    fn take_interrupt(&mut self) -> Result<(), Fault> {
+        let Interrupts {
+            vector,
+            saved_pc,
+            enable,
+        } = self.interrupts;
+        let read = |memory: &Memory, cell| {
+            memory.peek(cell).ok_or(Fault::new(FaultKind::ReadOutOfBounds, cell))
+        };
+        if !self.pending || self.shadow || *read(&self.memory, enable)? == 0 {
+            return Ok(());
+        }
+        self.pending = false;
+        self.memory.poke(saved_pc, self.pc())?;
+        self.memory.poke(enable, Value::new(0))?;
+        let handler = read(&self.memory, vector)?;
+        self.set_pc(handler);
+        Ok(())
+    }
+
+    /// Where the loaded program came from, empty for plain images.
+    pub fn debug_info(&self) -> &DebugInfo {
+        &self.debug_info
+    }
+
+    pub fn set_debug_info(&mut self, debug_info: DebugInfo) {
+        self.debug_info = debug_info;
+    }
+
+    /// Sends a record of every executed command to `trace`, `None` turns
+    /// tracing off.
+    pub fn set_trace(&mut self, trace: Option<Box<dyn Trace>>) {
+        self.trace = trace;
+        self.trace_error = None;
+    }
+
+    /// Why tracing stopped, if writing a record failed. The machine keeps
+    /// running without the trace.
+    pub fn trace_error(&self) -> Option<&std::io::Error> {
+        self.trace_error.as_ref()
+    }
+
+    /// Why the last [`RunOutcome::MacroFailed`] happened, rendered by the
+    /// compiler of the program.
+    pub fn macro_error(&self) -> Option<&terl::Error> {
+        self.macro_error.as_ref()
+    }
+
+    // the cells the operands of `command` point at, devices are not read
+    // because reading them may change them
+    fn operand_cells(&self, command: &Command) -> [Option<Value>; 2] {
+        let cell = |addr| match self.memory.is_mapped(addr) {
+            true => None,
+            false => self.memory.peek(addr),
+        };
+        [cell(command.a()), cell(command.b())]
+    }
+
+    /// Writes out what the trace still buffers, a failure is kept like one
+    /// while recording and stops tracing.
+    pub fn flush_trace(&mut self) {
+        if let Some(Err(e)) = self.trace.as_mut().map(|trace| trace.flush()) {
+            self.trace_error = Some(e);
+            self.trace = None;
+        }
+    }
+
+    /// Keeps the memory writes of the last `limit` steps so they can be
+    /// taken back with [`Machine::step_back`], `None` stops keeping them.
+    ///
+    /// Effects on devices, like written or consumed characters, are not
+    /// kept and can not be taken back.
+    pub fn set_history(&mut self, limit: Option<usize>) {
+        self.history = limit.map(|limit| (VecDeque::new(), limit));
+    }
+
+    /// Number of steps that can be taken back.
+    pub fn history_len(&self) -> usize {
+        self.history.as_ref().map_or(0, |(undos, _)| undos.len())
+    }
+
+    fn clear_history(&mut self) {
+        if let Some((undos, _)) = &mut self.history {
+            undos.clear();
+        }
+    }
+
+    /// Takes back the last step, false if there is none in the history.
+    pub fn step_back(&mut self) -> bool {
+        let Some(undo) = self.history.as_mut().and_then(|(undos, _)| undos.pop_back()) else {
+            return false;
+        };
+        for write in undo.writes.iter().rev() {
+            self.memory.undo(write);
+        }
+        self.cycles = undo.cycles;
+        self.pending = undo.pending;
+        self.shadow = undo.shadow;
+        true
+    }
+
+    /// Executes the command at the program counter, after taking a pending
+    /// interrupt.
+    ///
+    /// The step is kept in the history if [`Machine::set_history`] enabled
+    /// it, even if the machine stopped.
+    pub fn step(&mut self) -> ControlFlow<RunOutcome> {
+        let undo = Undo {
+            cycles: self.cycles,
+            pending: self.pending,
+            shadow: self.shadow,
+            writes: Vec::new(),
+        };
+        if self.trace.is_some() || self.history.is_some() {
+            self.memory.start_journal();
+        }
+        let flow = self.advance();
+        let writes = self.memory.take_journal();
+        if let Some((undos, limit)) = &mut self.history {
+            if undos.len() >= *limit {
+                undos.pop_front();
+            }
+            if *limit > 0 {
+                undos.push_back(Undo { writes, ..undo });
+            }
+        }
+        flow
+    }
+
+    // executes one step with the journal already running if the trace or
+    // history need it
+    fn advance(&mut self) -> ControlFlow<RunOutcome> {
+        if let Err(fault) = self.take_interrupt() {
+            return ControlFlow::Break(RunOutcome::Fault(fault.at(self.pc())));
+        }
+        let enabled = self.memory.peek(self.interrupts.enable);
+
+        let pc = self.pc();
+        if let Some(calls) = self.macro_calls.get(&pc) {
+            for call in calls {
+                if let Err(e) = call.call(&mut self.memory) {
+                    self.macro_error = Some(e);
+                    return ControlFlow::Break(RunOutcome::MacroFailed { pc });
+                }
+            }
+        }
+
+        let command = match self.memory.fetch(pc) {
+            Ok(command) => command,
+            Err(fault) => return ControlFlow::Break(RunOutcome::Fault(fault.at(pc))),
+        };
+
+        // the last command before the program counter overflows still runs,
+        // it only overflows if it neither halts nor jumps
+        let next = pc.next_command(self.memory.policy()).ok();
+        match next {
+            Some(next) => self.set_pc(next),
+            // the journal tells whether the command wrote the program counter
+            None if self.memory.journal.is_none() => self.memory.start_journal(),
+            None => {}
+        }
+        let before = self.trace.is_some().then(|| self.operand_cells(&command));
+        // the trace only shows the writes of the command itself
+        let executed_from = self.memory.journal().len();
+        let devices_from = self.memory.device_journal().len();
+        let executed = command.execute(&mut self.memory);
+        // a faulting command is traced with the writes it made before the fault
+        if let Some(before) = before {
+            let record = TraceRecord {
+                cycle: self.cycles,
+                pc,
+                command,
+                before,
+                after: self.operand_cells(&command),
+                writes: self.memory.journal()[executed_from..].to_vec(),
+                device_writes: self.memory.device_journal()[devices_from..].to_vec(),
+                location: self.debug_info.describe(pc),
+            };
+            if let Some(Err(e)) = self.trace.as_mut().map(|trace| trace.record(&record)) {
+                // keep running, but do not fail on every following command
+                self.trace_error = Some(e);
+                self.trace = None;
+            }
+        }
+        if let Err(fault) = executed {
+            return ControlFlow::Break(RunOutcome::Fault(fault.at(pc)));
+        }
+        self.cycles += 1;
+
+        let enabled_now = self.memory.peek(self.interrupts.enable);
+        self.shadow = enabled == Some(Value::new(0)) && enabled_now != enabled;
+        if self.memory.tick_devices() {
+            self.pending = true;
+        }
+
+        if command.op() == Op::Hlt {
+            return ControlFlow::Break(RunOutcome::Halted);
+        }
+        let writes = &self.memory.journal()[executed_from..];
+        if next.is_none() && !writes.iter().any(|write| write.addr == PC) {
+            return ControlFlow::Break(RunOutcome::PcOverflow { pc });
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// Runs until `stop` returns true before executing a command, which
+    /// yields [`ControlFlow::Continue`], or until the machine stops.
+    pub fn run_until(
+        &mut self,
+        max_steps: Option<usize>,
+        mut stop: impl FnMut(&Machine) -> bool,
+    ) -> ControlFlow<RunOutcome> {
+        let mut steps = 0;
+        loop {
+            if stop(self) {
+                return ControlFlow::Continue(());
+            }
+            if max_steps.is_some_and(|max| steps >= max) {
+                return ControlFlow::Break(RunOutcome::StepLimit { steps });
+            }
+            steps += 1;
+            self.step()?;
+        }
+    }
+
+    /// Runs until the machine stops, at most `max_steps` commands.
+    pub fn run(&mut self, max_steps: Option<usize>) -> RunOutcome {
+        match self.run_until(max_steps, |_| false) {
+            ControlFlow::Break(outcome) => outcome,
+            ControlFlow::Continue(()) => unreachable!("the stop condition never holds"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        devices::{Buffer, Console, Timer, CONSOLE_BASE, CONSOLE_SIZE, TIMER_BASE, TIMER_SIZE},
+        macros::Meta,
+        protection::Protection,
+        trace::JsonTrace,
+    };
+
+    // `commands` encoded one after another
+    fn encode(commands: &[(Op, u16, u16)]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for &(op, a, b) in commands {
+            let mut encoded = [0; Command::SIZE];
+            Command::new(op, Value::new(a), Value::new(b)).encode(&mut encoded);
+            bytes.extend(encoded);
+        }
+        bytes
+    }
+
+    // a machine running `commands`, placed one after another from `origin`
+    fn machine(origin: u16, commands: &[(Op, u16, u16)]) -> Machine {
+        let mut machine = Machine::new(0x10000);
+        machine.load(Value::new(origin), &encode(commands)).unwrap();
+        machine.set_entry(Value::new(origin));
+        machine
+    }
+
+    fn cell(machine: &Machine, addr: u16) -> u16 {
+        *machine.memory().read(Value::new(addr)).unwrap()
+    }
+
+    const HANDLER: u16 = 0xf100;
+
+    #[test]
+    fn last_command_runs_before_the_pc_overflows() {
+        let mut halting = machine(0xfffb, &[(Op::Hlt, 0, 0)]);
+        assert_eq!(halting.run(None), RunOutcome::Halted);
+
+        let mut jumping = machine(0xfffb, &[(Op::Set, 0x00, 0xf000)]);
+        assert_eq!(jumping.step(), ControlFlow::Continue(()));
+        assert_eq!(jumping.pc(), Value::new(0xf000));
+
+        let mut overflowing = machine(0xfffb, &[(Op::Set, 0x02, 1)]);
+        let outcome = overflowing.run(None);
+        assert_eq!(outcome, RunOutcome::PcOverflow { pc: Value::new(0xfffb) });
+        assert_eq!(overflowing.memory().read(Value::new(0x02)), Ok(Value::new(1)));
+    }
+
+    fn failing(_: &mut Memory, _: &[Meta]) -> Result<(), terl::Error> {
+        let message = terl::Message::text("failed".to_owned(), Arc::<str>::from("test.mc"));
+        Err(terl::Error::from(message))
+    }
+
+    #[test]
+    fn failing_macro_stops_the_machine() {
+        let mut machine = machine(0xf000, &[(Op::Hlt, 0, 0)]);
+        let call = MacroCall {
+            called: failing,
+            args: Vec::new(),
+        };
+        machine.macro_calls.insert(Value::new(0xf000), vec![call]);
+        let outcome = machine.run(None);
+        assert_eq!(outcome, RunOutcome::MacroFailed { pc: Value::new(0xf000) });
+        assert!(machine.macro_error().is_some());
+        assert_eq!(machine.cycles(), 0);
+    }
+
+    #[test]
+    fn timer_interrupts_after_its_period() {
+        let mut machine = machine(
+            0xf000,
+            &[
+                (Op::Set, 0x0a, HANDLER),
+                (Op::Set, *TIMER_BASE, 3),
+                (Op::Set, 0x0e, 1),
+                (Op::Set, 0x02, 1),
+                (Op::Set, 0x02, 2),
+            ],
+        );
+        machine.load(Value::new(HANDLER), &encode(&[(Op::Hlt, 0, 0)])).unwrap();
+        let timer = Box::new(Timer::new());
+        machine.memory_mut().map(TIMER_BASE, TIMER_SIZE, timer).unwrap();
+
+        // the command setting the period is the first of the three
+        assert_eq!(machine.run(None), RunOutcome::Halted);
+        assert_eq!(machine.cycles(), 5);
+        assert_eq!(cell(&machine, 0x02), 1);
+        assert_eq!(cell(&machine, 0x0c), 0xf014, "the interrupted command is saved");
+        assert_eq!(cell(&machine, 0x0e), 0, "interrupts are disabled in the handler");
+    }
+
+    #[test]
+    fn no_interrupt_right_after_enabling() {
+        let mut machine = machine(
+            0xf000,
+            &[
+                (Op::Set, 0x0a, HANDLER),
+                (Op::Set, 0x0e, 1),
+                (Op::Set, 0x02, 1),
+                (Op::Hlt, 0, 0),
+            ],
+        );
+        machine.load(Value::new(HANDLER), &encode(&[(Op::Hlt, 0, 0)])).unwrap();
+        machine.raise_interrupt();
+
+        for _ in 0..3 {
+            assert_eq!(machine.step(), ControlFlow::Continue(()));
+        }
+        assert_eq!(cell(&machine, 0x02), 1, "the command after enabling ran");
+        assert_eq!(machine.pc(), Value::new(0xf00f));
+        assert_eq!(machine.step(), ControlFlow::Break(RunOutcome::Halted));
+        assert_eq!(cell(&machine, 0x0c), 0xf00f);
+    }
+
+    #[test]
+    fn reti_returns_to_the_interrupted_command() {
+        let mut machine = machine(
+            0xf000,
+            &[
+                (Op::Set, 0x0a, HANDLER),
+                // points at the saved program counter
+                (Op::Set, 0x06, 0x0c),
+                (Op::Set, 0x0e, 1),
+                (Op::Set, 0x02, 1),
+                (Op::Hlt, 0, 0),
+            ],
+        );
+        let handler = [(Op::Set, 0x04, 7), (Op::Set, 0x0e, 1), (Op::Lod, 0x00, 0x06)];
+        machine.load(Value::new(HANDLER), &encode(&handler)).unwrap();
+        machine.raise_interrupt();
+
+        assert_eq!(machine.run(None), RunOutcome::Halted);
+        assert_eq!((cell(&machine, 0x02), cell(&machine, 0x04)), (1, 7));
+        assert_eq!(cell(&machine, 0x0c), 0xf014);
+        assert_eq!(machine.pc(), Value::new(0xf019));
+        assert_eq!(machine.cycles(), 8);
+    }
+
+    // everything a step may change, besides the devices
+    fn state(machine: &Machine) -> (Vec<u8>, usize, bool, bool) {
+        let memory = machine.memory().to_vec();
+        (memory, machine.cycles(), machine.pending, machine.shadow)
+    }
+
+    #[test]
+    fn step_back_restores_the_state() {
+        let mut machine = machine(
+            0xf000,
+            &[
+                (Op::Set, *TIMER_BASE, 1),
+                (Op::Set, 0x0e, 1),
+                (Op::Set, 0x02, 5),
+            ],
+        );
+        let timer = Box::new(Timer::new());
+        machine.memory_mut().map(TIMER_BASE, TIMER_SIZE, timer).unwrap();
+        machine.set_history(Some(10));
+
+        let mut states = Vec::new();
+        for _ in 0..3 {
+            states.push(state(&machine));
+            assert_eq!(machine.step(), ControlFlow::Continue(()));
+        }
+        // the timer raised an interrupt, which waits for the command after
+        // enabling them
+        assert!(machine.pending && !machine.shadow);
+        for expected in states.iter().rev() {
+            assert!(machine.step_back());
+            assert_eq!(&state(&machine), expected);
+        }
+        assert!(!machine.step_back());
+        assert_eq!(machine.pc(), Value::new(0xf000));
+    }
+
+    #[test]
+    fn history_keeps_the_latest_steps() {
+        let commands = [(Op::Set, 0x02, 1), (Op::Set, 0x02, 2), (Op::Set, 0x02, 3)];
+        let mut machine = machine(0xf000, &commands);
+        machine.set_history(Some(2));
+        for _ in 0..3 {
+            assert_eq!(machine.step(), ControlFlow::Continue(()));
+        }
+        assert_eq!(machine.history_len(), 2);
+        assert!(machine.step_back() && machine.step_back());
+        assert!(!machine.step_back());
+        assert_eq!((cell(&machine, 0x02), machine.pc()), (1, Value::new(0xf005)));
+    }
+
+    #[test]
+    fn stopping_steps_can_be_taken_back() {
+        let mut halting = machine(0xf000, &[(Op::Set, 0x02, 1), (Op::Hlt, 0, 0)]);
+        halting.set_history(Some(10));
+        assert_eq!(halting.run(None), RunOutcome::Halted);
+        assert!(halting.step_back());
+        assert_eq!((halting.pc(), halting.cycles()), (Value::new(0xf005), 1));
+        assert_eq!(halting.step(), ControlFlow::Break(RunOutcome::Halted));
+
+        let mut faulting = machine(0xf000, &[(Op::Set, 0x02, 1), (Op::Set, 0x20, 1)]);
+        let memory = faulting.memory_mut();
+        memory.protect(Value::new(0x20), 2, Protection::NoAccess).unwrap();
+        faulting.set_history(Some(10));
+        let outcome = faulting.run(None);
+        assert!(matches!(outcome, RunOutcome::Fault(_)), "{outcome}");
+        assert!(faulting.step_back());
+        assert_eq!((faulting.pc(), faulting.cycles()), (Value::new(0xf005), 1));
+        assert_eq!(cell(&faulting, 0x02), 1);
+    }
+
+    #[test]
+    fn device_writes_are_traced_without_reading_the_device() {
+        let mut machine = machine(0x100, &[(Op::Set, 0xff00, 0x41), (Op::Hlt, 0, 0)]);
+        let output = Buffer::new();
+        let console = Console::new(std::io::empty(), output.clone());
+        let memory = machine.memory_mut();
+        memory.map(CONSOLE_BASE, CONSOLE_SIZE, Box::new(console)).unwrap();
+        let trace = Buffer::new();
+        machine.set_trace(Some(Box::new(JsonTrace::new(trace.clone()))));
+
+        assert_eq!(machine.run(Some(10)), RunOutcome::Halted);
+        assert_eq!(output.contents(), b"A");
+        let trace = String::from_utf8(trace.contents()).unwrap();
+        let set = trace.lines().next().unwrap();
+        let cells = r#""before":[null,0],"after":[null,0],"writes":[],"#;
+        let device_writes = r#""device_writes":[{"addr":65280,"value":65}]"#;
+        assert!(set.contains(&format!("{cells}{device_writes}")), "{set}");
+    }
+
+    // takes records, but can not flush them
+    #[derive(Debug)]
+    struct Unflushable;
+
+    impl std::io::Write for Unflushable {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Err(std::io::Error::other("disk full"))
+        }
+    }
+
+    #[test]
+    fn failing_flush_is_kept() {
+        let mut machine = machine(0x100, &[(Op::Hlt, 0, 0)]);
+        machine.set_trace(Some(Box::new(JsonTrace::new(Unflushable))));
+        assert_eq!(machine.run(Some(10)), RunOutcome::Halted);
+        assert!(machine.trace_error().is_none());
+
+        machine.flush_trace();
+        let error = machine.trace_error().expect("the flush failed");
+        assert_eq!(error.to_string(), "disk full");
+    }
+}
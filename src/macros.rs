@@ -7,14 +7,15 @@ use terl::{Error, FileBuffer, MakeError};
 
 use crate::{compiler::Compiler, parser::Ident, Memory, Value};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Meta {
     pub id: Ident,
     pub val: Option<Value>,
 }
 
 pub type VirtualCall = fn(mem: &mut Memory, metas: &[Meta]) -> Result<(), Error>;
-pub type Preprocess = fn(c: &mut Compiler, args: &[Ident]) -> Result<(), Error>;
+/// Runs while compiling, `called` is the name of the macro where it is used.
+pub type Preprocess = fn(c: &mut Compiler, called: &Ident, args: &[Ident]) -> Result<(), Error>;
 
 #[derive(Debug, Clone, Copy)]
 pub enum Macro {
@@ -24,15 +25,29 @@ pub enum Macro {
 
 impl Macro {}
 
+/// A [`VirtualCall`] the machine runs before executing the command at its
+/// address.
+#[derive(Debug, Clone)]
+pub struct MacroCall {
+    pub called: VirtualCall,
+    pub args: Vec<Meta>,
+}
+
+impl MacroCall {
+    pub fn call(&self, memory: &mut Memory) -> Result<(), Error> {
+        (self.called)(memory, &self.args)
+    }
+}
+
 fn print_mem(mem: &mut Memory, metas: &[Meta]) -> Result<(), Error> {
     for arg in metas.iter() {
-        let val = arg.val.map(|v| mem.read(v));
+        let val = arg.val.and_then(|v| mem.peek(v));
         println!("print_mem: {} -> {:?}", arg.id, val);
     }
     Ok(())
 }
 
-fn include(c: &mut Compiler, args: &[Ident]) -> Result<(), Error> {
+fn include(c: &mut Compiler, _called: &Ident, args: &[Ident]) -> Result<(), Error> {
     for file_name in args {
         let source = std::fs::read_to_string(file_name.literal().as_str()).map_err(|e| {
             let reason = format!("failed to read file `{}`: {}", file_name.literal(), e);
@@ -47,9 +62,34 @@ fn include(c: &mut Compiler, args: &[Ident]) -> Result<(), Error> {
     Ok(())
 }
 
+// `#alloc NAME [words]`, reserves one cell unless `words` are given
+fn alloc(c: &mut Compiler, called: &Ident, args: &[Ident]) -> Result<(), Error> {
+    match args {
+        [name] => c.allocate(&c.local_name(name), &name.with_literal("1")),
+        [name, words] => c.allocate(&c.local_name(name), words),
+        _ => Err(called.make_error("expect a name and an optional number of words")),
+    }
+}
+
+// `#alloc_region START END`, where the following `#alloc`s reserve cells
+fn alloc_region(c: &mut Compiler, called: &Ident, args: &[Ident]) -> Result<(), Error> {
+    match args {
+        [start, end] => c.set_alloc_region(start, end),
+        _ => Err(called.make_error("expect the start and end of the region")),
+    }
+}
+
+// `#gensym NAME...`, gives names a unique meaning in a function body
+fn gensym(c: &mut Compiler, called: &Ident, args: &[Ident]) -> Result<(), Error> {
+    c.compile_gensym(called, args)
+}
+
 pub static MACROS: LazyLock<HashMap<&'static str, Macro>> = LazyLock::new(|| {
     HashMap::from([
         ("print_mem", Macro::Fn(print_mem)),
         ("include", Macro::Preprocess(include)),
+        ("alloc", Macro::Preprocess(alloc)),
+        ("alloc_region", Macro::Preprocess(alloc_region)),
+        ("gensym", Macro::Preprocess(gensym)),
     ])
 });
@@ -1,297 +1,318 @@
-#![feature(str_as_str)]
-pub mod compiler;
-pub mod macros;
-pub mod parser;
-
-use std::{
-    num::ParseIntError,
-    ops::{Deref, DerefMut},
-    str::FromStr,
-    sync::Arc,
+mod cli;
+
+use std::{io::Write, path::Path, process::ExitCode};
+
+use mini_cpu::{
+    bus::Device,
+    compiler::{Compiler, Program},
+    debugger::Debugger,
+    devices::{Console, Timer, CONSOLE_SIZE, TIMER_SIZE},
+    disasm,
+    image::{Format, Image, ImageError},
+    machine::Machine,
+    protection::Protection,
+    snapshot::Snapshot,
+    symbols::Symbols,
+    trace::{JsonTrace, TextTrace, Trace},
+    Fault, RunOutcome, Value,
 };
 
-#[derive(Debug, Clone, Copy)]
-enum Op {
-    Neq,
-    Sub,
-    Set,
-    Shr,
-    Lod,
-    Str,
-}
-
-impl Op {
-    fn execute(&self, mem: &mut Memory, a: Value, b: Value) {
-        match self {
-            // *a !== *b
-            Op::Neq => {
-                let a = mem.read(a);
-                let b = mem.read(b);
-                let neq = a != b;
-                mem.write(a, (neq as u16).into());
-            }
-            // *a -= *b
-            Op::Sub => {
-                let tmp = mem.read(a);
-                let (result, _) = tmp.0.overflowing_sub(mem.read(b).0);
-                mem.write(a, result.into());
-            }
-            // *a = b
-            Op::Set => {
-                mem.write(a, b);
-            }
-
-            // *a >>= *b
-            Op::Shr => {
-                let tmp = mem.read(a);
-                let (result, _) = tmp.0.overflowing_shr(mem.read(b).0 as u32);
-                mem.write(a, result.into());
-            }
-            // *a = **b
-            Op::Lod => {
-                let ptr = mem.read(b);
-                let data = mem.read(ptr);
-                mem.write(a, data);
-            }
-            // **b = *a
-            Op::Str => {
-                let data = mem.read(a);
-                let ptr = mem.read(b);
+fn compile(path: &Path) -> Result<Compiler, String> {
+    let src = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read file `{}`: {}", path.display(), e))?;
 
-                mem.write(ptr, data);
-            }
-        }
-        // *c = a
-        // b = *c
+    let mut compiler = Compiler::new();
+    match compiler.compile_str(&path.display().to_string(), &src) {
+        Ok(()) => Ok(compiler),
+        Err(e) => Err(compiler.handle_error(&e).unwrap_or_else(|e| e)),
     }
 }
 
-#[derive(Debug)]
-pub struct InvalidOp;
-
-impl FromStr for Op {
-    type Err = InvalidOp;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "NEQ" => Ok(Op::Neq),
-            "SUB" => Ok(Op::Sub),
-            "SET" => Ok(Op::Set),
-            "SHR" => Ok(Op::Shr),
-            "LOD" => Ok(Op::Lod),
-            "STR" => Ok(Op::Str),
-            _ => Err(InvalidOp),
-        }
-    }
+fn program(path: &Path, origin: Value) -> Result<Program, String> {
+    program_with_compiler(path, origin).map(|(program, _)| program)
 }
 
-impl TryFrom<u8> for Op {
-    type Error = InvalidOp;
-
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
-        match value {
-            1 => Ok(Op::Neq),
-            2 => Ok(Op::Sub),
-            3 => Ok(Op::Set),
-            4 => Ok(Op::Shr),
-            5 => Ok(Op::Lod),
-            6 => Ok(Op::Str),
-            _ => Err(InvalidOp),
-        }
+fn program_with_compiler(path: &Path, origin: Value) -> Result<(Program, Compiler), String> {
+    let compiler = compile(path)?;
+    match compiler.program(origin) {
+        Ok(program) => Ok((program, compiler)),
+        Err(e) => Err(compiler.handle_error(&e).unwrap_or_else(|e| e)),
     }
 }
 
-impl From<Op> for u8 {
-    fn from(value: Op) -> Self {
-        match value {
-            Op::Neq => 1,
-            Op::Sub => 2,
-            Op::Set => 3,
-            Op::Shr => 4,
-            Op::Lod => 5,
-            Op::Str => 6,
+/// Reads `path` as an image with its entry, if its extension names an image
+/// format. Flat binaries are loaded at and start from `load`.
+fn read_image(path: &Path, load: Value) -> Result<Option<(Image, Value)>, String> {
+    let Some(format) = Format::from_path(path) else {
+        return Ok(None);
+    };
+    let read_error = |e: std::io::Error| format!("failed to read file `{}`: {}", path.display(), e);
+    let image_error = |e: ImageError| format!("{}: {}", path.display(), e);
+
+    let (image, entry) = match format {
+        Format::Bin => {
+            let bytes = std::fs::read(path).map_err(read_error)?;
+            (Image::from_flat(load, bytes), None)
         }
-    }
-}
-
-#[derive(Debug, Clone, Copy)]
-pub struct Command {
-    op: Op,
-    a: Value,
-    b: Value,
-}
-
-impl Command {
-    fn new(op: Op, a: Value, b: Value) -> Command {
-        Command { op, a, b }
-    }
-
-    fn execute(&self, mem: &mut Memory) {
-        self.op.execute(mem, self.a, self.b)
-    }
-
-    fn encode(&self, memory: &mut [u8]) {
-        assert!(memory.len() >= 5);
-        // 1 + 2 + 2 = 5bytes
-        memory[0] = self.op.into();
-        memory[1..3].copy_from_slice(&self.a.to_le_bytes());
-        memory[3..5].copy_from_slice(&self.b.to_le_bytes());
-    }
-
-    fn decode(memory: &[u8]) -> Result<Command, InvalidOp> {
-        assert!(memory.len() >= 5);
-        let operator = Op::try_from(memory[0])?;
-        let a = Value(u16::from_le_bytes(memory[1..3].try_into().unwrap()));
-        let b = Value(u16::from_le_bytes(memory[3..5].try_into().unwrap()));
-        Ok(Command::new(operator, a, b))
-    }
+        Format::Ihex => {
+            let text = std::fs::read_to_string(path).map_err(read_error)?;
+            Image::read_ihex(&text).map_err(image_error)?
+        }
+        Format::Srec => {
+            let text = std::fs::read_to_string(path).map_err(read_error)?;
+            Image::read_srec(&text).map_err(image_error)?
+        }
+    };
+    let entry = entry.or(image.origin()).unwrap_or(load);
+    Ok(Some((image, entry)))
 }
 
-trait Encode {
-    fn encode(&mut self, memory: &mut [u8]);
+fn read_symbols(path: &Path) -> Result<Symbols, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read file `{}`: {}", path.display(), e))?;
+    Symbols::read(&text).map_err(|e| format!("{}: {}", path.display(), e))
 }
 
-impl Encode for &Command {
-    fn encode(&mut self, memory: &mut [u8]) {
-        Command::encode(self, memory);
-    }
+fn write_layout(args: &cli::Args, program: &Program) -> Result<(), String> {
+    let Some(path) = &args.layout else {
+        return Ok(());
+    };
+    std::fs::write(path, program.layout())
+        .map_err(|e| format!("failed to write file `{}`: {}", path.display(), e))
 }
 
-impl<E, I> Encode for I
-where
-    E: Encode,
-    I: Iterator<Item = E>,
-{
-    fn encode(&mut self, mut memory: &mut [u8]) {
-        for mut command in self {
-            command.encode(&mut memory[..5]);
-            memory = &mut memory[5..];
+fn open_trace(sink: &cli::TraceSink, json: bool) -> Result<Option<Box<dyn Trace>>, String> {
+    fn boxed<W: Write + std::fmt::Debug + 'static>(out: W, json: bool) -> Box<dyn Trace> {
+        match json {
+            true => Box::new(JsonTrace::new(out)),
+            false => Box::new(TextTrace::new(out)),
         }
     }
-}
-
-pub struct Memory<'m> {
-    memory: &'m mut [u8],
-}
-
-impl Memory<'_> {
-    fn new(memory: &mut [u8]) -> Memory {
-        Memory { memory }
-    }
-
-    fn eval(&mut self, pc: Value) -> Result<(), InvalidOp> {
-        let pc_val = self.read(pc);
-        let command = Command::decode(&self.memory[pc_val.0 as usize..])?;
-        println!("eval: {command:?} at {}", pc_val.0);
-        command.execute(self);
-        Ok(())
-    }
 
-    fn read(&self, ptr: Value) -> Value {
-        let index = ptr.0 as usize;
-
-        let memory: [u8; 2] = self.memory[index..index + 2].try_into().unwrap();
-        Value(u16::from_le_bytes(memory))
-    }
-
-    fn write(&mut self, ptr: Value, value: Value) {
-        let (Value(ptr), Value(value)) = (ptr, value);
-        self.memory[ptr as usize..ptr as usize + 2].copy_from_slice(&value.to_le_bytes());
-    }
+    let trace = match sink {
+        cli::TraceSink::Off => return Ok(None),
+        cli::TraceSink::Stderr => boxed(std::io::stderr(), json),
+        cli::TraceSink::File(path) => {
+            let file = std::fs::File::create(path)
+                .map_err(|e| format!("failed to create file `{}`: {}", path.display(), e))?;
+            boxed(std::io::BufWriter::new(file), json)
+        }
+    };
+    Ok(Some(trace))
 }
 
-impl<'m> From<&'m mut [u8]> for Memory<'m> {
-    fn from(memory: &'m mut [u8]) -> Self {
-        Memory { memory }
+// a failing trace does not stop the machine, so it is only reported
+fn report_trace_error(machine: &Machine) {
+    if let Some(e) = machine.trace_error() {
+        eprintln!("tracing stopped: {e}");
     }
 }
 
-impl Deref for Memory<'_> {
-    type Target = [u8];
-
-    fn deref(&self) -> &Self::Target {
-        self.memory
+fn check_fits(image: &Image, memory: usize) -> Result<(), String> {
+    if image.end() > memory {
+        let reason = format!(
+            "program ending at {:#x} does not fit in {} bytes of memory",
+            image.end(),
+            memory
+        );
+        return Err(reason);
     }
+    Ok(())
 }
 
-impl DerefMut for Memory<'_> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        self.memory
+/// Creates a machine running the image or source file of `args`, with the
+/// names known in it and the compiler of a source file.
+fn load(args: &cli::Args) -> Result<(Machine, Symbols, Option<Compiler>), String> {
+    let mut machine = Machine::new(args.memory);
+    machine.set_policy(args.edge);
+    if let Some(base) = args.console {
+        // the debugger reads its commands from stdin, so a debugged program
+        // only gets the console's output
+        let console: Box<dyn Device> = match args.command {
+            cli::Subcommand::Debug => Box::new(Console::new(std::io::empty(), std::io::stdout())),
+            _ => Box::new(Console::host()),
+        };
+        machine.memory_mut().map(base, CONSOLE_SIZE, console)?;
     }
-}
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub struct Value(u16);
-
-impl core::fmt::Display for Value {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "0x{:04x}", self.0)
+    if let Some(base) = args.timer {
+        let timer = Box::new(Timer::new());
+        machine.memory_mut().map(base, TIMER_SIZE, timer)?;
     }
-}
-
-#[derive(Debug, Clone, Copy)]
-struct Aborted;
-
-impl Value {
-    const fn new(value: u16) -> Value {
-        Value(value)
+    for (start, len, protection) in &args.protect {
+        machine.memory_mut().protect(*start, *len, *protection)?;
     }
 
-    fn next_command(&self) -> Result<Value, Aborted> {
-        self.0.checked_add(5).map(Value).ok_or(Aborted)
+    let load_error = |fault: Fault| format!("failed to load `{}`: {fault}", args.path.display());
+    let (image, symbols, compiler) = match read_image(&args.path, args.load)? {
+        Some((image, entry)) => {
+            check_fits(&image, args.memory)?;
+            machine.load_image(&image).map_err(load_error)?;
+            machine.set_entry(entry);
+            let symbols = match &args.symbols {
+                Some(path) => read_symbols(path)?,
+                None => Symbols::new(),
+            };
+            (image, symbols, None)
+        }
+        None => {
+            let (program, compiler) = program_with_compiler(&args.path, args.load)?;
+            check_fits(&program.image, args.memory)?;
+            machine.load_program(&program).map_err(load_error)?;
+            (program.image, program.symbols, Some(compiler))
+        }
+    };
+    if let Some(entry) = args.entry {
+        machine.set_entry(entry);
     }
-}
 
-impl Deref for Value {
-    type Target = u16;
-
-    fn deref(&self) -> &Self::Target {
-        &self.0
+    if args.rom {
+        for segment in image.segments.iter().filter(|segment| !segment.bytes.is_empty()) {
+            let len = u16::try_from(segment.bytes.len())
+                .map_err(|_| format!("segment at {} is too large for a ROM", segment.origin))?;
+            machine
+                .memory_mut()
+                .protect(segment.origin, len, Protection::ReadOnly)
+                .map_err(|e| format!("can not make the program read-only: {e}"))?;
+        }
     }
-}
-
-impl DerefMut for Value {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+    if let Some(path) = &args.snapshot {
+        let bytes = std::fs::read(path)
+            .map_err(|e| format!("failed to read file `{}`: {}", path.display(), e))?;
+        let snapshot = Snapshot::decode(&bytes).map_err(|e| format!("{}: {}", path.display(), e))?;
+        machine
+            .restore(&snapshot)
+            .map_err(|e| format!("can not restore `{}`: {}", path.display(), e))?;
     }
+    Ok((machine, symbols, compiler))
 }
 
-impl From<u16> for Value {
-    fn from(value: u16) -> Self {
-        Value(value)
+fn run(args: &cli::Args) -> Result<(), String> {
+    match args.command {
+        cli::Subcommand::Check => {
+            let program = program(&args.path, args.load)?;
+            write_layout(args, &program)?;
+        }
+        cli::Subcommand::Build => {
+            let program = program(&args.path, args.load)?;
+            write_layout(args, &program)?;
+            let format = args
+                .format
+                .or_else(|| args.output.as_deref().and_then(Format::from_path))
+                .unwrap_or(Format::Bin);
+            let output = args
+                .output
+                .clone()
+                .unwrap_or_else(|| args.path.with_extension(format.extension()));
+
+            let (origin, bytes) = program.image.flatten();
+            let size = bytes.len();
+            // a flat binary only keeps where it is loaded
+            let entry = program.entry;
+            if format == Format::Bin && entry != origin {
+                let output = output.display();
+                eprintln!("warning: {output} starts at {origin}, not at its entry {entry}");
+                eprintln!("         run it with `--entry {entry}`");
+            }
+            let contents = match format {
+                Format::Bin => bytes,
+                Format::Ihex => program.image.write_ihex(Some(program.entry)).into_bytes(),
+                Format::Srec => program.image.write_srec(Some(program.entry)).into_bytes(),
+            };
+            std::fs::write(&output, contents)
+                .map_err(|e| format!("failed to write file `{}`: {}", output.display(), e))?;
+            if let Some(path) = &args.symbols {
+                std::fs::write(path, program.symbols.write())
+                    .map_err(|e| format!("failed to write file `{}`: {}", path.display(), e))?;
+            }
+            println!(
+                "{}: {size} bytes at {origin}, entry {}",
+                output.display(),
+                program.entry
+            );
+        }
+        cli::Subcommand::Run => {
+            let (mut machine, _, compiler) = load(args)?;
+            machine.set_trace(open_trace(&args.trace, args.trace_json)?);
+            let outcome = machine.run(args.max_steps);
+            machine.flush_trace();
+            report_trace_error(&machine);
+            if let Some(path) = &args.save_snapshot {
+                std::fs::write(path, machine.snapshot().encode())
+                    .map_err(|e| format!("failed to write file `{}`: {}", path.display(), e))?;
+            }
+            if let (RunOutcome::Fault(fault), Some(compiler)) = (outcome, &compiler) {
+                let rendered = compiler.handle_fault(&fault, machine.debug_info());
+                return Err(rendered.unwrap_or_else(|e| e));
+            }
+            let macro_error = machine.macro_error();
+            if let (RunOutcome::MacroFailed { .. }, Some(error), Some(compiler)) =
+                (outcome, macro_error, &compiler)
+            {
+                let rendered = compiler.handle_error(error);
+                return Err(rendered.unwrap_or_else(|e| e));
+            }
+            if outcome != RunOutcome::Halted {
+                let pc = outcome.pc().unwrap_or(machine.pc());
+                let location = match machine.debug_info().describe(pc) {
+                    Some(location) => format!(" at {location}"),
+                    None => String::new(),
+                };
+                return Err(format!("program stopped: {outcome}{location}"));
+            }
+        }
+        cli::Subcommand::Debug => {
+            let (mut machine, symbols, _) = load(args)?;
+            machine.set_trace(open_trace(&args.trace, args.trace_json)?);
+            let mut debugger = Debugger::new(machine, symbols);
+            let repl = debugger.repl(std::io::stdin().lock(), std::io::stdout());
+            let mut machine = debugger.into_machine();
+            machine.flush_trace();
+            report_trace_error(&machine);
+            repl.map_err(|e| format!("debugger failed: {e}"))?;
+        }
+        cli::Subcommand::Disasm => {
+            let (image, symbols) = match read_image(&args.path, args.load)? {
+                Some((image, _)) => (image, Symbols::new()),
+                None => {
+                    let program = program(&args.path, args.load)?;
+                    (program.image, program.symbols)
+                }
+            };
+            // a symbol file replaces the names of a compiled program
+            let symbols = match &args.symbols {
+                Some(path) => read_symbols(path)?,
+                None => symbols,
+            };
+            let (origin, bytes) = image.flatten();
+            let listing = disasm::listing(&disasm::decode(origin, &bytes), &symbols);
+            match &args.output {
+                Some(output) => std::fs::write(output, listing)
+                    .map_err(|e| format!("failed to write file `{}`: {}", output.display(), e))?,
+                None => print!("{listing}"),
+            }
+        }
     }
+    Ok(())
 }
 
-impl FromStr for Value {
-    type Err = ParseIntError;
+fn main() -> ExitCode {
+    let args = match cli::Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(cli::ArgsError::Help) => {
+            print!("{}", cli::USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(cli::ArgsError::Invalid(reason)) => {
+            eprintln!("error: {reason}\n\n{}", cli::USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if let Some(str) = s.strip_prefix("0x") {
-            u16::from_str_radix(str, 16).map(Value)
-        } else if let Some(str) = s.strip_prefix("0b") {
-            u16::from_str_radix(str, 2).map(Value)
-        } else if let Some(str) = s.strip_prefix("0o") {
-            u16::from_str_radix(str, 8).map(Value)
-        } else {
-            u16::from_str(s).map(Value)
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
         }
     }
 }
-
-fn main() {
-    let mut memory = [0u8; 65536];
-    let mut memory = Memory::new(&mut memory);
-
-    let src = std::fs::read_to_string("code.mc").unwrap();
-    let buffer = terl::FileBuffer::new("code.mc".into(), src.chars().collect());
-    let buffer = Arc::new(buffer);
-
-    let mut compiler = compiler::Compiler::new();
-    compiler
-        .compile_file(buffer.clone())
-        .map_err(|e| compiler.handle_error(&e).unwrap())
-        .map_err(|e| println!("{e}"))
-        .unwrap();
-
-    compiler.run(Value::new(0xf000), &mut memory);
-}
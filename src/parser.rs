@@ -56,7 +56,7 @@ impl Ident {
         skip_whitespace(p);
         p.start_taking();
         let mut ident = String::new();
-        while let Some(c) = p.next_if(|c| !c.is_whitespace() && *c != '=' && *c != ';') {
+        while let Some(c) = p.next_if(|c| !c.is_whitespace() && !matches!(*c, '=' | ';' | ':')) {
             ident.push(*c);
         }
 
@@ -80,6 +80,15 @@ impl Ident {
     pub fn path(&self) -> &Arc<str> {
         &self.buf_name
     }
+
+    /// An ident reading `literal` written where `self` was, for names and
+    /// numbers made up by the compiler.
+    pub fn with_literal(&self, literal: &str) -> Ident {
+        Ident {
+            literal: literal.into(),
+            ..self.clone()
+        }
+    }
 }
 
 impl terl::WithSpan for Ident {
@@ -100,10 +109,212 @@ impl core::fmt::Display for Ident {
     }
 }
 
-#[derive(Debug)]
+fn is_atom_char(c: &char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(*c, '_' | '.' | '$')
+}
+
+/// A constant expression, evaluated once the layout is known
+#[derive(Debug, Clone)]
+pub enum Expr {
+    /// a name, a number, or `.` for the address of the current command or
+    /// data
+    Atom(Ident),
+    Unary {
+        op: Ident,
+        operand: Box<Expr>,
+    },
+    Binary {
+        op: Ident,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    /// `=value`, the address of a cell in the constant pool holding `value`
+    Constant {
+        marker: Ident,
+        value: Box<Expr>,
+    },
+}
+
+impl Expr {
+    fn binding_power(op: &Ident) -> u8 {
+        match op.literal().as_str() {
+            "|" => 1,
+            "^" => 2,
+            "&" => 3,
+            "<<" | ">>" => 4,
+            "+" | "-" => 5,
+            _ => 6,
+        }
+    }
+
+    fn parse_operator(p: &mut Parser<char>, unary: bool) -> terl::Result<Ident, terl::ParseError> {
+        p.start_taking();
+        let mut op = String::new();
+        match p.peek().copied() {
+            Some(c @ ('-' | '~')) if unary => op.push(c),
+            Some(c @ ('+' | '-' | '*' | '/' | '%' | '&' | '|' | '^')) if !unary => op.push(c),
+            Some(c @ ('<' | '>')) if !unary => {
+                p.next();
+                if p.peek() != Some(&c) {
+                    return p.throw(format!("expect `{c}{c}`"));
+                }
+                op.push(c);
+                op.push(c);
+            }
+            _ => return p.unmatch("expect an operator"),
+        }
+        p.next();
+
+        Ok(Ident {
+            literal: op.into_boxed_str().into(),
+            buf_name: p.buffer().buf_name().clone(),
+            location: p.get_span(),
+        })
+    }
+
+    fn parse_unary(p: &mut Parser<char>, spaces: bool) -> terl::Result<Self, terl::ParseError> {
+        if spaces {
+            skip_whitespace(p);
+        }
+        let unary = |p: &mut Parser<char>| Self::parse_operator(p, true);
+        if let Some(op) = p.try_match(unary)? {
+            let operand = Box::new(Self::parse_unary(p, spaces)?);
+            return Ok(Expr::Unary { op, operand });
+        }
+
+        p.start_taking();
+        if p.next_if(|c| *c == '=').is_some() {
+            let marker = Ident {
+                literal: "=".into(),
+                buf_name: p.buffer().buf_name().clone(),
+                location: p.get_span(),
+            };
+            let value = Box::new(Self::parse_unary(p, false)?);
+            return Ok(Expr::Constant { marker, value });
+        }
+
+        if p.next_if(|c| *c == '(').is_some() {
+            let expr = Self::parse_binary(p, true)?;
+            skip_whitespace(p);
+            if p.next_if(|c| *c == ')').is_none() {
+                return p.throw("expect `)`");
+            }
+            return Ok(expr);
+        }
+
+        p.start_taking();
+        let mut atom = String::new();
+        while let Some(c) = p.next_if(is_atom_char) {
+            atom.push(*c);
+        }
+        if atom.is_empty() {
+            return p.unmatch("expect an expression");
+        }
+
+        Ok(Expr::Atom(Ident {
+            literal: atom.into_boxed_str().into(),
+            buf_name: p.buffer().buf_name().clone(),
+            location: p.get_span(),
+        }))
+    }
+
+    /// Parses operands joined by binary operators, whitespace between them is
+    /// only allowed if `spaces` is set or inside of parentheses.
+    fn parse_binary(p: &mut Parser<char>, spaces: bool) -> terl::Result<Self, terl::ParseError> {
+        fn reduce(operands: &mut Vec<Expr>, op: Ident) {
+            let rhs = Box::new(operands.pop().unwrap());
+            let lhs = Box::new(operands.pop().unwrap());
+            operands.push(Expr::Binary { op, lhs, rhs });
+        }
+
+        let mut operands = vec![Self::parse_unary(p, spaces)?];
+        let mut operators: Vec<Ident> = Vec::new();
+        let binary = |p: &mut Parser<char>| {
+            if spaces {
+                skip_whitespace(p);
+            }
+            let op = Self::parse_operator(p, false)?;
+            let rhs = Self::parse_unary(p, spaces)?;
+            Ok((op, rhs))
+        };
+        while let Some((op, rhs)) = p.try_match(binary)? {
+            while operators
+                .last()
+                .is_some_and(|top| Self::binding_power(top) >= Self::binding_power(&op))
+            {
+                reduce(&mut operands, operators.pop().unwrap());
+            }
+            operators.push(op);
+            operands.push(rhs);
+        }
+        while let Some(op) = operators.pop() {
+            reduce(&mut operands, op);
+        }
+
+        Ok(operands.pop().unwrap())
+    }
+
+    // whether the leftmost operand is a constant, like in `=1+2`
+    fn starts_with_constant(&self) -> bool {
+        match self {
+            Expr::Constant { .. } => true,
+            Expr::Binary { lhs, .. } => lhs.starts_with_constant(),
+            Expr::Atom(_) | Expr::Unary { .. } => false,
+        }
+    }
+
+    // the leftmost constant replaced by its value, which turns `=1+2` read
+    // as an operand into `1+2` read after the `=` of a define
+    fn without_constant(self) -> Expr {
+        match self {
+            Expr::Constant { value, .. } => *value,
+            Expr::Binary { op, lhs, rhs } => Expr::Binary {
+                op,
+                lhs: Box::new(lhs.without_constant()),
+                rhs,
+            },
+            other => other,
+        }
+    }
+}
+
+impl terl::WithSpan for Expr {
+    fn get_span(&self) -> Span {
+        match self {
+            Expr::Atom(atom) => atom.get_span(),
+            Expr::Unary { op, operand } => op.get_span() + operand.get_span(),
+            Expr::Binary { lhs, rhs, .. } => lhs.get_span() + rhs.get_span(),
+            Expr::Constant { marker, value } => marker.get_span() + value.get_span(),
+        }
+    }
+}
+
+impl terl::WithBufName for Expr {
+    fn buf_name(&self) -> &Arc<str> {
+        match self {
+            Expr::Atom(atom) => atom.buf_name(),
+            Expr::Unary { op, .. } => op.buf_name(),
+            Expr::Binary { lhs, .. } => lhs.buf_name(),
+            Expr::Constant { marker, .. } => marker.buf_name(),
+        }
+    }
+}
+
+impl core::fmt::Display for Expr {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Expr::Atom(atom) => write!(f, "{atom}"),
+            Expr::Unary { op, operand } => write!(f, "{op}{operand}"),
+            Expr::Binary { op, lhs, rhs } => write!(f, "({lhs} {op} {rhs})"),
+            Expr::Constant { value, .. } => write!(f, "={value}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Define {
     pub name: Ident,
-    pub value: Ident,
+    pub value: Expr,
 }
 
 impl Define {
@@ -111,11 +322,148 @@ impl Define {
         let name = p.parse(Ident::parse)?;
         skip_whitespace(p);
         parse_char(p, '=')?;
-        let value = p.parse(Ident::parse)?;
+        let value = Expr::parse_binary(p, true)?;
         Ok(Define { name, value })
     }
 }
 
+/// `name:`, the address of the next command
+#[derive(Debug)]
+pub struct Label {
+    pub name: Ident,
+}
+
+impl Label {
+    fn parse(p: &mut Parser<char>) -> terl::Result<Self, terl::ParseError> {
+        let name = p.parse(Ident::parse)?;
+        parse_char(p, ':')?;
+        Ok(Label { name })
+    }
+}
+
+/// A string in double quotes, with the escapes `\n`, `\t`, `\0`, `\\`
+/// and `\"`, as its UTF-8 bytes
+fn parse_text(p: &mut Parser<char>) -> terl::Result<Vec<u8>, terl::ParseError> {
+    p.start_taking();
+    parse_char(p, '"')?;
+    let mut text = String::new();
+    loop {
+        match p.next().copied() {
+            Some('"') => return Ok(text.into_bytes()),
+            Some('\\') => {
+                let escaped = match p.next().copied() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('0') => '\0',
+                    Some(c @ ('\\' | '"')) => c,
+                    _ => return p.throw("unknown escape in string"),
+                };
+                text.push(escaped);
+            }
+            Some('\n') | None => return p.throw("unterminated string"),
+            Some(c) => text.push(c),
+        }
+    }
+}
+
+/// An argument of `.bytes`
+#[derive(Debug, Clone)]
+pub enum BytesArg {
+    Text(Vec<u8>),
+    /// a single byte
+    Value(Expr),
+}
+
+#[derive(Debug, Clone)]
+pub enum DataKind {
+    /// `.word`, little endian 16-bit words
+    Words(Vec<Expr>),
+    /// `.bytes`, strings and single bytes
+    Bytes(Vec<BytesArg>),
+    /// `.zero n`, `n` zero bytes
+    Zero(Expr),
+    /// `.align n`, zero bytes up to the next multiple of `n`
+    Align(Expr),
+}
+
+/// Data laid out into the image between the commands
+#[derive(Debug, Clone)]
+pub struct Data {
+    pub directive: Ident,
+    pub kind: DataKind,
+}
+
+impl Data {
+    fn parse(p: &mut Parser<char>) -> terl::Result<Self, terl::ParseError> {
+        let directive = p.parse(Ident::parse)?;
+        let kind = match directive.literal().as_str() {
+            ".word" => DataKind::Words(p.parse(parse_operands)?),
+            ".bytes" => {
+                let mut args = Vec::new();
+                let parse_arg = |p: &mut Parser<char>| {
+                    skip_whitespace(p);
+                    terl::Try::<BytesArg, char>::new(p)
+                        .or_try(|p| p.parse(parse_text).map(BytesArg::Text))
+                        .or_try(|p| Expr::parse_binary(p, false).map(BytesArg::Value))
+                        .finish()
+                };
+                while let Some(arg) = p.try_match(parse_arg)? {
+                    args.push(arg);
+                }
+                DataKind::Bytes(args)
+            }
+            ".zero" => DataKind::Zero(Expr::parse_binary(p, true)?),
+            ".align" => DataKind::Align(Expr::parse_binary(p, true)?),
+            _ => return p.unmatch("expect a data directive"),
+        };
+        Ok(Data { directive, kind })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum LayoutKind {
+    /// `.org addr`, continue the current section at `addr`
+    Org(Expr),
+    /// `.section name [addr]`, continue the section `name`, a new section
+    /// starts at `addr`
+    Section { name: Ident, origin: Option<Expr> },
+}
+
+/// Where the following commands and data are placed
+#[derive(Debug, Clone)]
+pub struct Layout {
+    pub directive: Ident,
+    pub kind: LayoutKind,
+}
+
+impl Layout {
+    fn parse(p: &mut Parser<char>) -> terl::Result<Self, terl::ParseError> {
+        let directive = p.parse(Ident::parse)?;
+        let kind = match directive.literal().as_str() {
+            ".org" => LayoutKind::Org(Expr::parse_binary(p, true)?),
+            ".section" => {
+                let name = p.parse(Ident::parse)?;
+                let origin = p.try_match(|p: &mut Parser<char>| Expr::parse_binary(p, true))?;
+                LayoutKind::Section { name, origin }
+            }
+            _ => return p.unmatch("expect a layout directive"),
+        };
+        Ok(Layout { directive, kind })
+    }
+}
+
+pub fn parse_operands(p: &mut Parser<char>) -> terl::Result<Vec<Expr>, terl::ParseError> {
+    let mut operands = Vec::new();
+    let parse_operand = |p: &mut Parser<char>| {
+        skip_whitespace(p);
+        Expr::parse_binary(p, false)
+    };
+    while let Some(operand) = p.try_match(parse_operand)? {
+        operands.push(operand);
+    }
+    Ok(operands)
+}
+
 pub fn parse_args(p: &mut Parser<char>) -> terl::Result<Vec<Ident>, terl::ParseError> {
     let mut args = Vec::new();
     while let Some(ident) = p.try_match(Ident::parse)? {
@@ -147,15 +495,25 @@ fn parse_tab(p: &mut Parser<char>) -> terl::Result<(), terl::ParseError> {
 #[derive(Debug)]
 pub struct Calling {
     pub called: Ident,
-    pub args: Vec<Ident>,
+    pub args: Vec<Expr>,
 }
 
 impl Calling {
     fn parse(p: &mut Parser<char>) -> terl::Result<Self, terl::ParseError> {
         let called = p.parse(Ident::parse)?;
-        let args = p.parse(parse_args)?;
+        let args = p.parse(parse_operands)?;
         Ok(Calling { called, args })
     }
+
+    /// The define a [`Item::DefineOrCalling`] stands for when its name is
+    /// not called.
+    pub fn into_define(self) -> Define {
+        let [value] = <[Expr; 1]>::try_from(self.args).expect("a single operand was parsed");
+        Define {
+            name: self.called,
+            value: value.without_constant(),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -177,12 +535,14 @@ impl Macro {
 pub enum Stmt {
     Calling(Calling),
     Macro(Macro),
+    Label(Label),
 }
 
 impl Stmt {
     fn parse(p: &mut Parser<char>) -> terl::Result<Self, terl::ParseError> {
         terl::Try::<Stmt, char>::new(p)
             .or_try(|p| p.parse(Macro::parse).map(Stmt::Macro))
+            .or_try(|p| p.parse(Label::parse).map(Stmt::Label))
             .or_try(|p| p.parse(Calling::parse).map(Stmt::Calling))
             .finish()
     }
@@ -213,40 +573,71 @@ pub struct Function {
 #[derive(Debug)]
 pub enum Item {
     Define(Define),
+    /// `name =value`, a call with a constant operand if `name` is a command
+    /// or function and a define otherwise
+    DefineOrCalling(Calling),
     Function(Function),
     Calling(Calling),
     Macro(Macro),
+    Label(Label),
+    Data(Data),
+    Layout(Layout),
 }
 
 impl Item {
     fn parse(p: &mut Parser<char>) -> terl::Result<Self, terl::ParseError> {
         terl::Try::<Item, char>::new(p)
             .or_try(|p| {
-                let define = p.parse(Define::parse).map(Item::Define)?;
+                let label = p.parse(Label::parse).map(Item::Label)?;
                 p.parse(parse_eol)?;
-                Ok(define)
+                Ok(label)
             })
             .or_try(|p| {
                 let macro_ = p.parse(Macro::parse).map(Item::Macro)?;
                 p.parse(parse_eol)?;
                 Ok(macro_)
             })
+            .or_try(|p| {
+                let data = p.parse(Data::parse).map(Item::Data)?;
+                p.parse(parse_eol)?;
+                Ok(data)
+            })
+            .or_try(|p| {
+                let layout = p.parse(Layout::parse).map(Item::Layout)?;
+                p.parse(parse_eol)?;
+                Ok(layout)
+            })
+            .or_try(|p| {
+                let calling = p.parse(Calling::parse)?;
+                p.parse(parse_eol)?;
+                let args = calling.args.as_slice();
+                if !matches!(args, [operand] if operand.starts_with_constant()) {
+                    return p.unmatch("expect `name =value`");
+                }
+                Ok(Item::DefineOrCalling(calling))
+            })
+            .or_try(|p| {
+                let define = p.parse(Define::parse).map(Item::Define)?;
+                p.parse(parse_eol)?;
+                Ok(define)
+            })
             .or_try(|p| {
                 let name = p.parse(Ident::parse)?;
-                let args = p.parse(parse_args)?;
                 terl::Try::<Item, char>::new(p)
                     .or_try(|p| {
+                        let args = p.parse(parse_args)?;
                         skip_whitespace(p);
                         parse_char(p, '=')?;
                         p.parse(parse_eol)?;
                         let commands = p.parse(parse_stmts)?;
                         Ok(Item::Function(Function {
                             name: name.clone(),
-                            args: args.clone(),
+                            args,
                             body: commands,
                         }))
                     })
                     .or_try(|p| {
+                        let args = p.parse(parse_operands)?;
                         p.parse(parse_eol)?;
                         Ok(Item::Calling(Calling { called: name, args }))
                     })
@@ -0,0 +1,150 @@
+use std::str::FromStr;
+
+use crate::{Fault, FaultKind, Memory, Value};
+
+/// What a protected region of the memory allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protection {
+    /// Commands may be read and executed, but not written, like a ROM.
+    ReadOnly,
+    /// Commands may be executed, but their cells not read or written.
+    ExecuteOnly,
+    /// Every access faults.
+    NoAccess,
+}
+
+impl FromStr for Protection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ro" | "read-only" => Ok(Protection::ReadOnly),
+            "xo" | "execute-only" => Ok(Protection::ExecuteOnly),
+            "na" | "no-access" => Ok(Protection::NoAccess),
+            _ => Err(format!("unknown protection `{s}`")),
+        }
+    }
+}
+
+/// A protected range of addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: Value,
+    /// One past the last address of the region.
+    pub end: u32,
+    pub protection: Protection,
+}
+
+// the kinds of access checked against regions
+#[derive(Clone, Copy)]
+pub(crate) enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Memory {
+    /// Protects the `len` bytes starting at `start`, regions may not
+    /// overlap.
+    ///
+    /// Loading an image ignores protection, so a read-only program can
+    /// still be loaded into it.
+    pub fn protect(
+        &mut self,
+        start: Value,
+        len: u16,
+        protection: Protection,
+    ) -> Result<(), String> {
+        let region = Region {
+            start,
+            end: *start as u32 + len as u32,
+            protection,
+        };
+        if len == 0 {
+            return Err(format!("region at {start} protects no bytes"));
+        }
+        if region.end > 0x10000 {
+            return Err(format!("region at {start} reaches past 0xffff"));
+        }
+        let overlap = self.regions.iter().find(|other| {
+            (*other.start as u32) < region.end && (*start as u32) < other.end
+        });
+        if let Some(other) = overlap {
+            return Err(format!(
+                "region at {start} overlaps the region at {}..{:#06x}",
+                other.start, other.end
+            ));
+        }
+        self.regions.push(region);
+        Ok(())
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    // faults if an access of `len` bytes at `addr` is not allowed
+    pub(crate) fn check(&self, addr: Value, len: u32, access: Access) -> Result<(), Fault> {
+        let (start, end) = (*addr as u32, *addr as u32 + len);
+        for region in &self.regions {
+            if end <= *region.start as u32 || region.end <= start {
+                continue;
+            }
+            let kind = match (access, region.protection) {
+                (Access::Read, Protection::ReadOnly) => continue,
+                (Access::Execute, Protection::ReadOnly | Protection::ExecuteOnly) => continue,
+                (Access::Read, _) => FaultKind::ReadProtected,
+                (Access::Write, _) => FaultKind::WriteProtected,
+                (Access::Execute, _) => FaultKind::ExecuteProtected,
+            };
+            return Err(Fault::new(kind, addr));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Command, Op};
+
+    // reading, writing and executing the command at 0x10, in a region
+    // protected with `protection`
+    fn accesses(protection: Protection) -> [Result<(), FaultKind>; 3] {
+        let mut memory = Memory::new(0x100);
+        let mut command = [0; Command::SIZE];
+        Command::new(Op::Hlt, Value::new(0), Value::new(0)).encode(&mut command);
+        memory.load(Value::new(0x10), &command).unwrap();
+        memory.protect(Value::new(0x10), 5, protection).unwrap();
+        let kind = |fault: Fault| fault.kind;
+        [
+            memory.read(Value::new(0x10)).map(drop).map_err(kind),
+            memory.write(Value::new(0x12), Value::new(0)).map_err(kind),
+            memory.fetch(Value::new(0x10)).map(drop).map_err(kind),
+        ]
+    }
+
+    #[test]
+    fn read_only_faults_on_writes() {
+        let accesses = accesses(Protection::ReadOnly);
+        assert_eq!(accesses, [Ok(()), Err(FaultKind::WriteProtected), Ok(())]);
+    }
+
+    #[test]
+    fn execute_only_faults_on_reads_and_writes() {
+        let accesses = accesses(Protection::ExecuteOnly);
+        let faults = [FaultKind::ReadProtected, FaultKind::WriteProtected];
+        assert_eq!(accesses, [Err(faults[0]), Err(faults[1]), Ok(())]);
+    }
+
+    #[test]
+    fn no_access_faults_on_everything() {
+        let accesses = accesses(Protection::NoAccess);
+        let faults = [
+            FaultKind::ReadProtected,
+            FaultKind::WriteProtected,
+            FaultKind::ExecuteProtected,
+        ];
+        assert_eq!(accesses, faults.map(Err));
+    }
+}
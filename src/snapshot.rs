@@ -0,0 +1,149 @@
+use crate::{machine::PC, Value};
+
+// the first bytes of every snapshot file
+const MAGIC: &[u8; 8] = b"MCPUSNAP";
+
+/// Version of the snapshot format written by [`Snapshot::encode`].
+///
+/// Version 1 is laid out as, all numbers little endian:
+/// - the magic `MCPUSNAP` and the version as a u16
+/// - the cycle counter as a u64
+/// - a flags byte: bit 0 an interrupt is pending, bit 1 interrupts were just
+///   enabled
+/// - the memory size as a u32, followed by the memory
+/// - the number of devices as a u16, each followed by the start of its
+///   window as a u16, the size of its state as a u32 and the state
+pub const VERSION: u16 = 1;
+
+/// The complete state of a [`Machine`](crate::machine::Machine), taken with
+/// [`Machine::snapshot`](crate::machine::Machine::snapshot).
+///
+/// The program counter lives in the memory. How the machine is configured,
+/// like the mapped devices, protected regions and loaded program, is not part
+/// of it and has to match when restoring.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub memory: Vec<u8>,
+    pub cycles: u64,
+    pub pending: bool,
+    pub shadow: bool,
+    /// The state of every device, by the start of its window.
+    pub devices: Vec<(Value, Vec<u8>)>,
+}
+
+// reads the fields of an encoded snapshot one after another
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() < len {
+            return Err("snapshot ends early".to_owned());
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+}
+
+impl Snapshot {
+    /// The program counter the machine continues from.
+    pub fn pc(&self) -> Option<Value> {
+        let bytes = self.memory.get(*PC as usize..*PC as usize + 2)?;
+        Some(Value::new(u16::from_le_bytes([bytes[0], bytes[1]])))
+    }
+
+    /// Encodes the snapshot in the current [`VERSION`] of the format.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(VERSION.to_le_bytes());
+        bytes.extend(self.cycles.to_le_bytes());
+        bytes.push(self.pending as u8 | (self.shadow as u8) << 1);
+        bytes.extend((self.memory.len() as u32).to_le_bytes());
+        bytes.extend(&self.memory);
+        bytes.extend((self.devices.len() as u16).to_le_bytes());
+        for (start, state) in &self.devices {
+            bytes.extend(start.to_le_bytes());
+            bytes.extend((state.len() as u32).to_le_bytes());
+            bytes.extend(state);
+        }
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Snapshot, String> {
+        let mut reader = Reader { bytes };
+        if reader.take(MAGIC.len()).ok() != Some(MAGIC) {
+            return Err("not a snapshot".to_owned());
+        }
+        let version = u16::from_le_bytes(reader.array()?);
+        if version != VERSION {
+            return Err(format!("unsupported snapshot version {version}"));
+        }
+
+        let cycles = u64::from_le_bytes(reader.array()?);
+        let [flags] = reader.array()?;
+        let memory_size = u32::from_le_bytes(reader.array()?);
+        let memory = reader.take(memory_size as usize)?.to_vec();
+        let device_count = u16::from_le_bytes(reader.array()?);
+        let mut devices = Vec::with_capacity(device_count as usize);
+        for _ in 0..device_count {
+            let start = Value::new(u16::from_le_bytes(reader.array()?));
+            let state_size = u32::from_le_bytes(reader.array()?);
+            devices.push((start, reader.take(state_size as usize)?.to_vec()));
+        }
+        if !reader.bytes.is_empty() {
+            return Err("trailing bytes after the snapshot".to_owned());
+        }
+
+        Ok(Snapshot {
+            memory,
+            cycles,
+            pending: flags & 1 != 0,
+            shadow: flags & 2 != 0,
+            devices,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            memory: (0..=255).collect(),
+            cycles: 1234,
+            pending: true,
+            shadow: false,
+            devices: vec![(Value::new(0xff00), vec![b'x']), (Value::new(0xff08), vec![])],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        assert_eq!(Snapshot::decode(&snapshot().encode()), Ok(snapshot()));
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut bytes = snapshot().encode();
+        bytes[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        let reason = format!("unsupported snapshot version {}", VERSION + 1);
+        assert_eq!(Snapshot::decode(&bytes), Err(reason));
+    }
+
+    #[test]
+    fn rejects_broken_snapshots() {
+        let bytes = snapshot().encode();
+        assert_eq!(Snapshot::decode(b"MCPUSNA"), Err("not a snapshot".to_owned()));
+        let early = Snapshot::decode(&bytes[..bytes.len() - 1]);
+        assert_eq!(early, Err("snapshot ends early".to_owned()));
+        let trailing = Snapshot::decode(&[bytes, vec![0]].concat());
+        assert_eq!(trailing, Err("trailing bytes after the snapshot".to_owned()));
+    }
+}
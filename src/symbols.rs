@@ -0,0 +1,136 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use crate::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    /// The address of a command.
+    Label,
+    /// A named constant, usually the address of a cell.
+    Define,
+}
+
+impl SymbolKind {
+    fn name(&self) -> &'static str {
+        match self {
+            SymbolKind::Label => "label",
+            SymbolKind::Define => "define",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: Arc<str>,
+    pub value: Value,
+    pub kind: SymbolKind,
+}
+
+/// The names a program gave to values, used to make listings readable.
+#[derive(Debug, Clone, Default)]
+pub struct Symbols {
+    symbols: BTreeMap<Arc<str>, Symbol>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, name: Arc<str>, value: Value, kind: SymbolKind) {
+        let symbol = Symbol {
+            name: name.clone(),
+            value,
+            kind,
+        };
+        self.symbols.insert(name, symbol);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.get(name)
+    }
+
+    /// All symbols, ordered by name.
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.values()
+    }
+
+    /// A name for `value` among the symbols of `kinds`, earlier kinds are
+    /// preferred and ties are broken by name.
+    pub fn name_of(&self, value: Value, kinds: &[SymbolKind]) -> Option<&str> {
+        kinds
+            .iter()
+            .find_map(|kind| {
+                self.iter()
+                    .find(|symbol| symbol.kind == *kind && symbol.value == value)
+            })
+            .map(|symbol| &*symbol.name)
+    }
+
+    /// Writes one `value kind name` line per symbol.
+    pub fn write(&self) -> String {
+        let mut symbols = self.iter().collect::<Vec<_>>();
+        symbols.sort_by_key(|symbol| *symbol.value);
+        symbols
+            .into_iter()
+            .map(|symbol| format!("{} {} {}\n", symbol.value, symbol.kind.name(), symbol.name))
+            .collect()
+    }
+
+    /// Reads symbols written by [`Symbols::write`].
+    pub fn read(text: &str) -> Result<Symbols, String> {
+        let mut symbols = Symbols::new();
+        for (idx, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |reason: &str| format!("line {}: {reason}", idx + 1);
+
+            let mut fields = line.split_whitespace();
+            let (Some(value), Some(kind), Some(name), None) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                return Err(error("expect `value kind name`"));
+            };
+            let value = value.parse().map_err(|_| error("invalid value"))?;
+            let kind = match kind {
+                "label" => SymbolKind::Label,
+                "define" => SymbolKind::Define,
+                _ => return Err(error("kind must be `label` or `define`")),
+            };
+            symbols.insert(name.into(), value, kind);
+        }
+        Ok(symbols)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn written_symbols_read_back() {
+        let mut symbols = Symbols::new();
+        symbols.insert("loop".into(), Value::new(0xf005), SymbolKind::Label);
+        symbols.insert("X".into(), Value::new(0x10), SymbolKind::Define);
+        let text = symbols.write();
+        assert_eq!(text, "0x0010 define X\n0xf005 label loop\n");
+
+        let read = Symbols::read(&text).unwrap();
+        assert_eq!(read.iter().collect::<Vec<_>>(), symbols.iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn bad_lines_are_reported_with_their_number() {
+        let cases = [
+            ("0x10 define", "line 1: expect `value kind name`"),
+            ("0x10 label a b", "line 1: expect `value kind name`"),
+            ("\n0xzz label a", "line 2: invalid value"),
+            ("0x10 label a\n0x12 const b", "line 2: kind must be `label` or `define`"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(Symbols::read(text).unwrap_err(), expected, "{text:?}");
+        }
+    }
+}
@@ -0,0 +1,237 @@
+use std::{fmt::Write as _, io::Write};
+
+use crate::{bus::DeviceWrite, CellWrite, Command, Value};
+
+/// What happened while the machine executed one command.
+#[derive(Debug, Clone)]
+pub struct TraceRecord {
+    /// Number of commands executed before this one.
+    pub cycle: usize,
+    pub pc: Value,
+    pub command: Command,
+    /// The cells `a` and `b` point at before the command, `None` if they lie
+    /// outside the memory or are handled by a device.
+    pub before: [Option<Value>; 2],
+    /// The same cells after the command.
+    pub after: [Option<Value>; 2],
+    /// Every cell of the memory the command wrote, in order.
+    pub writes: Vec<CellWrite>,
+    /// Every value the command wrote to a device, in order.
+    pub device_writes: Vec<DeviceWrite>,
+    /// Where the command came from, if the program has debug info.
+    pub location: Option<String>,
+}
+
+/// Receives a [`TraceRecord`] for every command a
+/// [`Machine`](crate::machine::Machine) executes.
+///
+/// Tracing is off while the machine has no sink.
+pub trait Trace: std::fmt::Debug {
+    fn record(&mut self, record: &TraceRecord) -> std::io::Result<()>;
+
+    /// Writes out records the sink still buffers.
+    fn flush(&mut self) -> std::io::Result<()>;
+}
+
+fn cell(value: Option<Value>) -> String {
+    value.map_or_else(|| "-".to_owned(), |value| value.to_string())
+}
+
+/// Writes one human readable line per command, for example to stderr or a
+/// file.
+#[derive(Debug)]
+pub struct TextTrace<W> {
+    out: W,
+}
+
+impl<W: Write> TextTrace<W> {
+    pub fn new(out: W) -> Self {
+        TextTrace { out }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write + std::fmt::Debug> Trace for TextTrace<W> {
+    fn record(&mut self, record: &TraceRecord) -> std::io::Result<()> {
+        let mut line = format!("#{} {}: {}", record.cycle, record.pc, record.command);
+        for (idx, name) in ["a", "b"].into_iter().enumerate() {
+            let (before, after) = (cell(record.before[idx]), cell(record.after[idx]));
+            write!(line, ", *{name} {before} -> {after}").unwrap();
+        }
+        for write in &record.writes {
+            write!(line, ", [{}] <- {}", write.addr, write.new).unwrap();
+        }
+        for write in &record.device_writes {
+            write!(line, ", device [{}] <- {}", write.addr, write.value).unwrap();
+        }
+        if let Some(location) = &record.location {
+            write!(line, " at {location}").unwrap();
+        }
+        writeln!(self.out, "{line}")
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+fn json_cells(cells: [Option<Value>; 2]) -> String {
+    let number = |value: Option<Value>| match value {
+        Some(value) => (*value).to_string(),
+        None => "null".to_owned(),
+    };
+    format!("[{},{}]", number(cells[0]), number(cells[1]))
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => json += "\\\"",
+            '\\' => json += "\\\\",
+            c if c.is_control() => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json + "\""
+}
+
+/// Writes one JSON object per command, numbers are plain integers and absent
+/// cells are `null`.
+#[derive(Debug)]
+pub struct JsonTrace<W> {
+    out: W,
+}
+
+impl<W: Write> JsonTrace<W> {
+    pub fn new(out: W) -> Self {
+        JsonTrace { out }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write + std::fmt::Debug> Trace for JsonTrace<W> {
+    fn record(&mut self, record: &TraceRecord) -> std::io::Result<()> {
+        let writes = record
+            .writes
+            .iter()
+            .map(|write| {
+                let (addr, old, new) = (*write.addr, *write.old, *write.new);
+                format!(r#"{{"addr":{addr},"old":{old},"new":{new}}}"#)
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        let device_writes = record
+            .device_writes
+            .iter()
+            .map(|write| {
+                let (addr, value) = (*write.addr, *write.value);
+                format!(r#"{{"addr":{addr},"value":{value}}}"#)
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        let location = match &record.location {
+            Some(location) => json_string(location),
+            None => "null".to_owned(),
+        };
+        writeln!(
+            self.out,
+            "{{\"cycle\":{},\"pc\":{},\"command\":\"{}\",\"before\":{},\"after\":{},\
+             \"writes\":[{}],\"device_writes\":[{}],\"location\":{}}}",
+            record.cycle,
+            *record.pc,
+            record.command,
+            json_cells(record.before),
+            json_cells(record.after),
+            writes,
+            device_writes,
+            location
+        )
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Op;
+
+    // `SET` writing a cell, its `b` points past the end of the memory
+    fn set() -> TraceRecord {
+        TraceRecord {
+            cycle: 3,
+            pc: Value::new(0xf00a),
+            command: Command::new(Op::Set, Value::new(0x10), Value::new(0xffff)),
+            before: [Some(Value::new(1)), None],
+            after: [Some(Value::new(0xffff)), None],
+            writes: vec![CellWrite {
+                addr: Value::new(0x10),
+                old: Value::new(1),
+                new: Value::new(0xffff),
+            }],
+            device_writes: Vec::new(),
+            location: None,
+        }
+    }
+
+    // `STR` sending a character to the console
+    fn store() -> TraceRecord {
+        TraceRecord {
+            cycle: 4,
+            pc: Value::new(0xf00f),
+            command: Command::new(Op::Str, Value::new(0x12), Value::new(0x14)),
+            before: [Some(Value::new(0x41)), Some(Value::new(0xff00))],
+            after: [Some(Value::new(0x41)), Some(Value::new(0xff00))],
+            writes: Vec::new(),
+            device_writes: vec![DeviceWrite {
+                addr: Value::new(0xff00),
+                value: Value::new(0x41),
+            }],
+            location: Some("say \"hi\"\\\n".to_owned()),
+        }
+    }
+
+    #[test]
+    fn text_trace_writes_a_line_per_record() {
+        let mut trace = TextTrace::new(Vec::new());
+        trace.record(&set()).unwrap();
+        let mut store = store();
+        store.location = Some("pre.mc:48 in putc called from test.mc:5".to_owned());
+        trace.record(&store).unwrap();
+
+        let expected = "\
+#3 0xf00a: SET 0x0010 0xffff, *a 0x0001 -> 0xffff, *b - -> -, [0x0010] <- 0xffff
+#4 0xf00f: STR 0x0012 0x0014, *a 0x0041 -> 0x0041, *b 0xff00 -> 0xff00, \
+device [0xff00] <- 0x0041 at pre.mc:48 in putc called from test.mc:5
+";
+        assert_eq!(String::from_utf8(trace.into_inner()).unwrap(), expected);
+    }
+
+    #[test]
+    fn json_trace_writes_an_object_per_record() {
+        let mut trace = JsonTrace::new(Vec::new());
+        trace.record(&set()).unwrap();
+        trace.record(&store()).unwrap();
+
+        let expected = [
+            r#"{"cycle":3,"pc":61450,"command":"SET 0x0010 0xffff","before":[1,null],"#,
+            r#""after":[65535,null],"writes":[{"addr":16,"old":1,"new":65535}],"#,
+            r#""device_writes":[],"location":null}"#,
+            "\n",
+            r#"{"cycle":4,"pc":61455,"command":"STR 0x0012 0x0014","before":[65,65280],"#,
+            r#""after":[65,65280],"writes":[],"device_writes":[{"addr":65280,"value":65}],"#,
+            r#""location":"say \"hi\"\\\u000a"}"#,
+            "\n",
+        ];
+        assert_eq!(String::from_utf8(trace.into_inner()).unwrap(), expected.concat());
+    }
+}